@@ -0,0 +1,407 @@
+use std::io;
+
+use crate::meta::{self, Meta, PageInfo, MAX_PAGES, META_COUNT, META_SIZE, SECTOR_SIZE};
+
+pub use crate::meta::{NAME_LEN, TITLE_LEN};
+pub use crate::storage::{FileFlash, FlashStorage};
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppfsError {
+    #[error("flash access failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("no valid AppFS metadata found")]
+    NotFormatted,
+    #[error("AppFS of {0} bytes is too small")]
+    TooSmall(usize),
+    #[error("app {0:?} not found")]
+    NotFound(String),
+    #[error("invalid app name or title {0:?}")]
+    InvalidName(String),
+    #[error("not enough space: need {needed} pages, have {free}")]
+    NoSpace { needed: usize, free: usize },
+}
+
+type Result<T, E = AppfsError> = core::result::Result<T, E>;
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AppEntry {
+    pub name: String,
+    pub title: String,
+    pub version: u16,
+    pub size: u32,
+    first_page: usize,
+}
+
+impl AppEntry {
+    // The AppFS handle of the app: its first page. The badge bootloader
+    // starts apps by handle.
+    pub fn handle(&self) -> usize {
+        self.first_page
+    }
+}
+
+
+// AppFS as used by the MCH2022 launcher. See meta.rs for the layout.
+pub struct Appfs<F: FlashStorage> {
+    flash: F,
+    meta: Meta,
+    active_slot: usize,
+    page_count: usize,
+}
+
+impl<F: FlashStorage> Appfs<F> {
+    pub fn open(mut flash: F) -> Result<Self> {
+        let page_count = Self::page_count_for(&flash)?;
+
+        let mut best: Option<(usize, Meta)> = None;
+        let mut buf = vec![0u8; META_SIZE];
+        for slot in 0..META_COUNT {
+            flash.read(slot * META_SIZE, &mut buf)?;
+            if let Some(meta) = Meta::parse(&buf) {
+                if best.as_ref().is_none_or(|(_, b)| newer(meta.serial, b.serial)) {
+                    best = Some((slot, meta));
+                }
+            }
+        }
+
+        let Some((active_slot, meta)) = best else {
+            return Err(AppfsError::NotFormatted);
+        };
+        Ok(Self { flash, meta, active_slot, page_count })
+    }
+
+    // Wipes all apps.
+    pub fn format(mut flash: F) -> Result<Self> {
+        let page_count = Self::page_count_for(&flash)?;
+        flash.erase(0, SECTOR_SIZE)?;
+        let meta = Meta::empty(1);
+        flash.write(0, &meta.serialize())?;
+        Ok(Self { flash, meta, active_slot: 0, page_count })
+    }
+
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    pub fn list(&self) -> Vec<AppEntry> {
+        self.pages()
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| page.is_file_start())
+            .map(|(idx, page)| AppEntry {
+                name: meta::from_field(&page.name),
+                title: meta::from_field(&page.title),
+                version: page.version,
+                size: page.size,
+                first_page: idx,
+            })
+            .collect()
+    }
+
+    pub fn find(&self, name: &str) -> Option<AppEntry> {
+        self.list().into_iter().find(|entry| entry.name == name)
+    }
+
+    pub fn free_space(&self) -> usize {
+        self.pages().iter().filter(|page| page.is_free()).count() * SECTOR_SIZE
+    }
+
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>> {
+        let entry = self.find(name).ok_or_else(|| AppfsError::NotFound(name.to_string()))?;
+        let mut data = vec![0u8; entry.size as usize];
+        for (chunk, page) in data.chunks_mut(SECTOR_SIZE).zip(self.chain(entry.first_page)) {
+            self.flash.read(page_offset(page), chunk)?;
+        }
+        Ok(data)
+    }
+
+    // Store an app, replacing any app with the same name. The old app
+    // stays intact until the new one is completely written.
+    pub fn write(&mut self, name: &str, title: &str, version: u16, data: &[u8]) -> Result<AppEntry> {
+        let name_field = meta::to_field(name)
+            .filter(|_| !name.is_empty())
+            .ok_or_else(|| AppfsError::InvalidName(name.to_string()))?;
+        let title_field = meta::to_field(title)
+            .ok_or_else(|| AppfsError::InvalidName(title.to_string()))?;
+        let needed = data.len().div_ceil(SECTOR_SIZE).max(1);
+        let free: Vec<usize> = self.pages()
+            .iter()
+            .enumerate()
+            .filter(|(_, page)| page.is_free())
+            .map(|(idx, _)| idx)
+            .take(needed)
+            .collect();
+        if free.len() < needed {
+            return Err(AppfsError::NoSpace { needed, free: free.len() });
+        }
+
+        for (chunk, &page) in data.chunks(SECTOR_SIZE).zip(&free) {
+            self.flash.erase(page_offset(page), SECTOR_SIZE)?;
+            self.flash.write(page_offset(page), chunk)?;
+        }
+
+        let mut meta = self.meta.clone();
+        if let Some(old) = self.find(name) {
+            for page in self.chain(old.first_page) {
+                meta.pages[page] = PageInfo::free();
+            }
+        }
+        for (i, &page) in free.iter().enumerate() {
+            let mut info = PageInfo::continuation();
+            if i == 0 {
+                info.name = name_field;
+                info.title = title_field;
+                // Fits; there are at most MAX_PAGES of 64KiB.
+                info.size = data.len() as u32;
+                info.version = version;
+            }
+            info.next = free.get(i + 1).map_or(0, |&next| next as u8);
+            meta.pages[page] = info;
+        }
+        self.commit(meta)?;
+
+        log::info!("appfs: wrote {:?} ({} bytes, {} pages)", name, data.len(), needed);
+        self.find(name).ok_or_else(|| AppfsError::NotFound(name.to_string()))
+    }
+
+    // Only the metadata is updated; the data pages are erased when they
+    // are used again.
+    pub fn delete(&mut self, name: &str) -> Result<()> {
+        let entry = self.find(name).ok_or_else(|| AppfsError::NotFound(name.to_string()))?;
+        let mut meta = self.meta.clone();
+        for page in self.chain(entry.first_page) {
+            meta.pages[page] = PageInfo::free();
+        }
+        self.commit(meta)?;
+        log::info!("appfs: deleted {:?}", name);
+        Ok(())
+    }
+
+    fn page_count_for(flash: &F) -> Result<usize> {
+        let size = flash.size();
+        if size < 2 * SECTOR_SIZE {
+            return Err(AppfsError::TooSmall(size));
+        }
+        Ok((size / SECTOR_SIZE - 1).min(MAX_PAGES))
+    }
+
+    // Only the pages that fit in this partition.
+    fn pages(&self) -> &[PageInfo] {
+        &self.meta.pages[..self.page_count]
+    }
+
+    fn chain(&self, first_page: usize) -> Vec<usize> {
+        let mut chain = vec![first_page];
+        let mut page = first_page;
+        loop {
+            let next = self.meta.pages[page].next as usize;
+            // Chains only go up; anything else is corrupt or the end.
+            if next <= page || next >= self.page_count {
+                break;
+            }
+            chain.push(next);
+            page = next;
+        }
+        chain
+    }
+
+    // Write meta to the inactive slot with a higher serial.
+    fn commit(&mut self, mut meta: Meta) -> Result<()> {
+        meta.serial = self.meta.serial.wrapping_add(1);
+        let slot = (self.active_slot + 1) % META_COUNT;
+        self.flash.erase(slot * META_SIZE, META_SIZE)?;
+        self.flash.write(slot * META_SIZE, &meta.serialize())?;
+        self.meta = meta;
+        self.active_slot = slot;
+        Ok(())
+    }
+}
+
+
+fn page_offset(page: usize) -> usize {
+    (page + 1) * SECTOR_SIZE
+}
+
+
+// Serials wrap (see commit()), so 0 is newer than u32::MAX.
+fn newer(serial: u32, than: u32) -> bool {
+    (serial.wrapping_sub(than) as i32) > 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::meta::DESC_SIZE;
+
+    // NOR flash in memory: erase sets bits, write only clears them.
+    struct MemFlash(Vec<u8>);
+
+    impl MemFlash {
+        fn new(pages: usize) -> Self {
+            Self(vec![0xFF; (pages + 1) * SECTOR_SIZE])
+        }
+    }
+
+    impl FlashStorage for MemFlash {
+        fn size(&self) -> usize {
+            self.0.len()
+        }
+
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+            buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+            for (old, new) in self.0[offset..offset + data.len()].iter_mut().zip(data) {
+                *old &= new;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, offset: usize, len: usize) -> io::Result<()> {
+            self.0[offset..offset + len].fill(0xFF);
+            Ok(())
+        }
+    }
+
+    fn app_data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|idx| (idx % 251) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn format_and_open() {
+        assert!(matches!(Appfs::open(MemFlash::new(4)), Err(AppfsError::NotFormatted)));
+        assert!(matches!(Appfs::format(MemFlash(vec![0xFF; SECTOR_SIZE])), Err(AppfsError::TooSmall(_))));
+
+        let appfs = Appfs::format(MemFlash::new(4)).unwrap();
+        assert!(appfs.list().is_empty());
+        assert_eq!(appfs.free_space(), 4 * SECTOR_SIZE);
+
+        let appfs = Appfs::open(appfs.into_inner()).unwrap();
+        assert!(appfs.list().is_empty());
+        assert_eq!(appfs.free_space(), 4 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn round_trip_over_several_pages() {
+        let mut appfs = Appfs::format(MemFlash::new(8)).unwrap();
+        let big = app_data(3 * SECTOR_SIZE + 100, 0x5A);
+        let small = app_data(10, 0xA5);
+
+        let entry = appfs.write("big", "Big app", 3, &big).unwrap();
+        assert_eq!((entry.title.as_str(), entry.version, entry.size), ("Big app", 3, big.len() as u32));
+        appfs.write("small", "Small app", 1, &small).unwrap();
+        assert_eq!(appfs.free_space(), 3 * SECTOR_SIZE);
+
+        let mut appfs = Appfs::open(appfs.into_inner()).unwrap();
+        let entries: Vec<(String, usize)> = appfs.list()
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry.handle()))
+            .collect();
+        assert_eq!(entries, [("big".to_string(), 0), ("small".to_string(), 4)]);
+        assert_eq!(appfs.read("big").unwrap(), big);
+        assert_eq!(appfs.read("small").unwrap(), small);
+        assert!(matches!(appfs.read("other"), Err(AppfsError::NotFound(_))));
+    }
+
+    #[test]
+    fn replace_app_with_same_name() {
+        let mut appfs = Appfs::format(MemFlash::new(4)).unwrap();
+        appfs.write("app", "Old", 1, &app_data(SECTOR_SIZE + 1, 1)).unwrap();
+        let new = app_data(SECTOR_SIZE / 2, 2);
+        appfs.write("app", "New", 2, &new).unwrap();
+
+        let mut appfs = Appfs::open(appfs.into_inner()).unwrap();
+        let entries = appfs.list();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].title.as_str(), entries[0].version), ("New", 2));
+        assert_eq!(appfs.read("app").unwrap(), new);
+        assert_eq!(appfs.free_space(), 3 * SECTOR_SIZE);
+    }
+
+    #[test]
+    fn delete_frees_pages() {
+        let mut appfs = Appfs::format(MemFlash::new(4)).unwrap();
+        appfs.write("one", "One", 1, &app_data(2 * SECTOR_SIZE, 1)).unwrap();
+        appfs.write("two", "Two", 1, &app_data(1, 2)).unwrap();
+        appfs.delete("one").unwrap();
+        assert!(matches!(appfs.delete("one"), Err(AppfsError::NotFound(_))));
+
+        let mut appfs = Appfs::open(appfs.into_inner()).unwrap();
+        assert_eq!(appfs.list().len(), 1);
+        assert_eq!(appfs.free_space(), 3 * SECTOR_SIZE);
+        // The freed pages get erased and reused.
+        let data = app_data(3 * SECTOR_SIZE, 3);
+        appfs.write("three", "Three", 1, &data).unwrap();
+        assert_eq!(appfs.read("three").unwrap(), data);
+    }
+
+    #[test]
+    fn no_space() {
+        let mut appfs = Appfs::format(MemFlash::new(3)).unwrap();
+        let err = appfs.write("huge", "Huge", 1, &app_data(3 * SECTOR_SIZE + 1, 0)).unwrap_err();
+        assert!(matches!(err, AppfsError::NoSpace { needed: 4, free: 3 }));
+
+        // Replacing needs room for both copies.
+        appfs.write("app", "App", 1, &app_data(2 * SECTOR_SIZE, 0)).unwrap();
+        let err = appfs.write("app", "App", 2, &app_data(2 * SECTOR_SIZE, 1)).unwrap_err();
+        assert!(matches!(err, AppfsError::NoSpace { needed: 2, free: 1 }));
+        assert_eq!(appfs.find("app").unwrap().version, 1);
+    }
+
+    #[test]
+    fn open_picks_highest_serial() {
+        let mut appfs = Appfs::format(MemFlash::new(4)).unwrap();
+        // Serial 2 in slot 1, serial 3 in slot 0.
+        appfs.write("first", "First", 1, &[1]).unwrap();
+        appfs.write("second", "Second", 1, &[2]).unwrap();
+        let appfs = Appfs::open(appfs.into_inner()).unwrap();
+        assert_eq!(appfs.list().len(), 2);
+
+        // A newer copy wins, whichever slot it is in.
+        let mut flash = appfs.into_inner();
+        flash.erase(META_SIZE, META_SIZE).unwrap();
+        flash.write(META_SIZE, &Meta::empty(7).serialize()).unwrap();
+        let appfs = Appfs::open(flash).unwrap();
+        assert!(appfs.list().is_empty());
+
+        // A copy with a bad crc is ignored.
+        let mut flash = appfs.into_inner();
+        flash.write(META_SIZE + 2 * DESC_SIZE, &[0]).unwrap();
+        assert_eq!(Appfs::open(flash).unwrap().list().len(), 2);
+    }
+
+    #[test]
+    fn serial_wraps() {
+        let write_meta = |flash: &mut MemFlash, slot: usize, meta: &Meta| {
+            flash.erase(slot * META_SIZE, META_SIZE).unwrap();
+            flash.write(slot * META_SIZE, &meta.serialize()).unwrap();
+        };
+
+        // Serial 0 after u32::MAX is the newer one.
+        let mut flash = MemFlash::new(4);
+        write_meta(&mut flash, 0, &Meta::empty(u32::MAX - 1));
+        write_meta(&mut flash, 1, &Meta::empty(u32::MAX));
+        let mut appfs = Appfs::open(flash).unwrap();
+        appfs.write("app", "App", 1, &[1]).unwrap();
+        let mut flash = appfs.into_inner();
+        let mut buf = vec![0u8; META_SIZE];
+        flash.read(0, &mut buf).unwrap();
+        assert_eq!(Meta::parse(&buf).unwrap().serial, 0);
+        assert!(Appfs::open(flash).unwrap().find("app").is_some());
+
+        let mut flash = MemFlash::new(4);
+        write_meta(&mut flash, 0, &Meta::empty(u32::MAX));
+        let mut with_app = Appfs::format(MemFlash::new(4)).unwrap();
+        with_app.write("app", "App", 1, &[1]).unwrap();
+        let mut meta = with_app.meta.clone();
+        meta.serial = 0;
+        write_meta(&mut flash, 1, &meta);
+        assert!(Appfs::open(flash).unwrap().find("app").is_some());
+    }
+}
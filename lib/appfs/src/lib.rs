@@ -0,0 +1,4 @@
+pub mod appfs;
+
+mod meta;
+mod storage;
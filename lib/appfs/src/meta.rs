@@ -0,0 +1,157 @@
+// On-flash AppFS metadata, as written by the MCH2022 launcher.
+//
+// The first 64KiB sector holds two 32KiB metadata copies. The valid one
+// with the highest serial wins; updates go to the other copy, so a
+// power loss halfway leaves the old one intact. Each copy is a 128 byte
+// header followed by one 128 byte descriptor per data page. Data page n
+// lives at (n + 1) * 64KiB.
+pub const SECTOR_SIZE: usize = 0x10000;
+pub const META_SIZE: usize = SECTOR_SIZE / 2;
+pub const META_COUNT: usize = 2;
+pub const DESC_SIZE: usize = 128;
+pub const MAX_PAGES: usize = META_SIZE / DESC_SIZE - 1;
+
+pub const NAME_LEN: usize = 48;
+pub const TITLE_LEN: usize = 64;
+
+const MAGIC: &[u8; 8] = b"AppFsDsc";
+
+const USE_FREE: u8 = 0xFF;
+const USE_DATA: u8 = 0x00;
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PageInfo {
+    pub name: [u8; NAME_LEN],
+    pub title: [u8; TITLE_LEN],
+    pub size: u32,
+    // Next page of the same file, 0 if none. Page 0 is always the start
+    // of a file, since files never point back to a lower page.
+    pub next: u8,
+    pub used: u8,
+    pub version: u16,
+}
+
+impl PageInfo {
+    pub fn free() -> Self {
+        Self {
+            name: [0xFF; NAME_LEN],
+            title: [0xFF; TITLE_LEN],
+            size: 0xFFFF_FFFF,
+            next: 0xFF,
+            used: USE_FREE,
+            version: 0xFFFF,
+        }
+    }
+
+    // A page of a file that is not its first page.
+    pub fn continuation() -> Self {
+        Self { used: USE_DATA, ..Self::free() }
+    }
+
+    pub fn is_free(&self) -> bool {
+        self.used == USE_FREE
+    }
+
+    // First pages carry the name; continuation pages don't.
+    pub fn is_file_start(&self) -> bool {
+        self.used == USE_DATA && self.name[0] != 0xFF
+    }
+
+    fn parse(buf: &[u8]) -> Self {
+        let mut name = [0u8; NAME_LEN];
+        name.copy_from_slice(&buf[0..48]);
+        let mut title = [0u8; TITLE_LEN];
+        title.copy_from_slice(&buf[48..112]);
+        Self {
+            name,
+            title,
+            size: u32::from_le_bytes(buf[112..116].try_into().unwrap()),
+            next: buf[116],
+            used: buf[117],
+            version: u16::from_le_bytes(buf[118..120].try_into().unwrap()),
+        }
+    }
+
+    fn serialize(&self, buf: &mut [u8]) {
+        buf[0..48].copy_from_slice(&self.name);
+        buf[48..112].copy_from_slice(&self.title);
+        buf[112..116].copy_from_slice(&self.size.to_le_bytes());
+        buf[116] = self.next;
+        buf[117] = self.used;
+        buf[118..120].copy_from_slice(&self.version.to_le_bytes());
+        buf[120..128].fill(0xFF);
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Meta {
+    pub serial: u32,
+    pub pages: Vec<PageInfo>,
+}
+
+impl Meta {
+    pub fn empty(serial: u32) -> Self {
+        Self { serial, pages: vec![PageInfo::free(); MAX_PAGES] }
+    }
+
+    // None if this is not a valid metadata copy.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if &buf[0..8] != MAGIC {
+            return None;
+        }
+        let serial = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+        let crc = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        let descs = &buf[DESC_SIZE..META_SIZE];
+        if crc32(descs) != crc {
+            log::warn!("appfs: metadata with serial {} has a bad crc", serial);
+            return None;
+        }
+        let pages = descs.chunks_exact(DESC_SIZE).map(PageInfo::parse).collect();
+        Some(Self { serial, pages })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![0xFFu8; META_SIZE];
+        for (page, chunk) in self.pages.iter().zip(buf[DESC_SIZE..].chunks_exact_mut(DESC_SIZE)) {
+            page.serialize(chunk);
+        }
+        let crc = crc32(&buf[DESC_SIZE..]);
+        buf[0..8].copy_from_slice(MAGIC);
+        buf[8..12].copy_from_slice(&self.serial.to_le_bytes());
+        buf[12..16].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+}
+
+
+// Fixed size, NUL padded string field.
+pub fn to_field<const N: usize>(s: &str) -> Option<[u8; N]> {
+    let bytes = s.as_bytes();
+    // Keep room for the terminating NUL, like the C side does.
+    if bytes.len() >= N || bytes.contains(&0) {
+        return None;
+    }
+    let mut field = [0u8; N];
+    field[..bytes.len()].copy_from_slice(bytes);
+    Some(field)
+}
+
+pub fn from_field(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0 || b == 0xFF).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+
+// CRC-32 (IEEE), same as esp_rom_crc32_le(0, ...).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
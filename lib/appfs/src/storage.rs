@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+
+// Where the AppFS lives: the appfs partition on the badge, or a plain
+// image file (a dump of that partition) on the host.
+//
+// Offsets are relative to the start of the AppFS. Like NOR flash, a
+// region must be erased (to 0xFF) before it is written.
+pub trait FlashStorage {
+    fn size(&self) -> usize;
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()>;
+    fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()>;
+    fn erase(&mut self, offset: usize, len: usize) -> io::Result<()>;
+}
+
+
+pub struct FileFlash {
+    file: File,
+    size: usize,
+}
+
+impl FileFlash {
+    // Use an existing image; its length is the AppFS size.
+    pub fn open(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len() as usize;
+        Ok(Self { file, size })
+    }
+
+    // Make file an erased image of size bytes.
+    pub fn create(file: File, size: usize) -> io::Result<Self> {
+        file.set_len(0)?;
+        let mut flash = Self { file, size };
+        flash.erase(0, size)?;
+        Ok(flash)
+    }
+
+    pub fn into_inner(self) -> File {
+        self.file
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> io::Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("range {:#x}+{:#x} outside of image of {:#x}", offset, len, self.size))),
+        }
+    }
+}
+
+impl FlashStorage for FileFlash {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(offset, buf.len())?;
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(buf)
+    }
+
+    // Writing can only clear bits, as on NOR flash, so an image behaves
+    // like the real partition when something forgets to erase.
+    fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        let mut buf = vec![0u8; data.len()];
+        self.read(offset, &mut buf)?;
+        for (old, new) in buf.iter_mut().zip(data) {
+            *old &= new;
+        }
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.write_all(&buf)
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> io::Result<()> {
+        self.check_range(offset, len)?;
+        const CHUNK: [u8; 4096] = [0xFF; 4096];
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        let mut left = len;
+        while left > 0 {
+            let n = left.min(CHUNK.len());
+            self.file.write_all(&CHUNK[..n])?;
+            left -= n;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn file_write_only_clears_bits() {
+        let path = std::env::temp_dir().join(format!("appfs-storage-{}.img", std::process::id()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut flash = FileFlash::create(file, 0x2000).unwrap();
+        let mut buf = [0u8; 2];
+
+        flash.write(0x1000, &[0x0F, 0xF0]).unwrap();
+        flash.write(0x1000, &[0x3C, 0x3C]).unwrap();
+        flash.read(0x1000, &mut buf).unwrap();
+        assert_eq!(buf, [0x0C, 0x30]);
+
+        flash.erase(0x1000, 0x1000).unwrap();
+        flash.write(0x1000, &[0x3C, 0x3C]).unwrap();
+        flash.read(0x1000, &mut buf).unwrap();
+        assert_eq!(buf, [0x3C, 0x3C]);
+
+        assert!(flash.write(0x1FFF, &[0, 0]).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use crate::ws2812::Rgb;
+
+
+// Frame generation only; no I/O here. LedAnimator (in animator.rs)
+// renders these at a fixed rate and pushes them to the RP2040.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Animation {
+    Solid(Rgb),
+    // Color wheel spread over the LEDs, rotating once per period.
+    Rainbow { period: Duration },
+    // Fade in and out once per period.
+    Breathing { color: Rgb, period: Duration },
+    // A single lit LED running over the strip once per period.
+    Chase { color: Rgb, background: Rgb, period: Duration },
+    // On for half a period, off for the other half, count times.
+    Blink { color: Rgb, count: u32, period: Duration },
+}
+
+impl Animation {
+    // Total running time, or None for animations that loop forever (or
+    // blink so long that it does not fit a Duration).
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            Animation::Blink { count, period, .. } => period.checked_mul(*count),
+            _ => None,
+        }
+    }
+
+    // Render the frame at `elapsed` since the animation started into
+    // `leds`. Returns false (and leaves `leds` alone) once the animation
+    // has finished.
+    pub fn render(&self, elapsed: Duration, leds: &mut [Rgb]) -> bool {
+        if let Some(duration) = self.duration() {
+            if elapsed >= duration {
+                return false;
+            }
+        }
+
+        match *self {
+            Animation::Solid(color) => {
+                leds.fill(color);
+            },
+            Animation::Rainbow { period } => {
+                let offset = phase(elapsed, period);
+                let n = leds.len().max(1) as u32;
+                for (idx, led) in leds.iter_mut().enumerate() {
+                    let pos = offset as u32 + (idx as u32 * 256) / n;
+                    *led = wheel(pos as u8);
+                }
+            },
+            Animation::Breathing { color, period } => {
+                // Triangle wave, squared so it looks more linear to the eye.
+                let p = phase(elapsed, period) as u32;
+                let tri = if p < 128 { p * 2 } else { (255 - p) * 2 };
+                let level = ((tri * tri) / 255) as u8;
+                leds.fill(scale(color, level));
+            },
+            Animation::Chase { color, background, period } => {
+                let lit = (phase(elapsed, period) as usize * leds.len()) / 256;
+                for (idx, led) in leds.iter_mut().enumerate() {
+                    *led = if idx == lit { color } else { background };
+                }
+            },
+            Animation::Blink { color, period, .. } => {
+                let on = phase(elapsed, period) < 128;
+                leds.fill(if on { color } else { Rgb::BLACK });
+            },
+        }
+        true
+    }
+}
+
+
+// Position within the current period, as 0..=255.
+fn phase(elapsed: Duration, period: Duration) -> u8 {
+    let period = period.as_millis().max(1);
+    ((elapsed.as_millis() % period) * 256 / period) as u8
+}
+
+// Scale color by level/255.
+pub fn scale(color: Rgb, level: u8) -> Rgb {
+    let f = |c: u8| ((c as u16 * level as u16) / 255) as u8;
+    Rgb::new(f(color.r), f(color.g), f(color.b))
+}
+
+// Classic color wheel: red -> green -> blue -> red.
+pub fn wheel(pos: u8) -> Rgb {
+    match pos {
+        0..=84 => Rgb::new(255 - pos * 3, pos * 3, 0),
+        85..=169 => {
+            let pos = pos - 85;
+            Rgb::new(0, 255 - pos * 3, pos * 3)
+        },
+        _ => {
+            let pos = pos - 170;
+            Rgb::new(pos * 3, 0, 255 - pos * 3)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_millis(1000);
+
+    fn render(animation: &Animation, elapsed_ms: u64, len: usize) -> Option<Vec<Rgb>> {
+        let mut leds = vec![Rgb::new(1, 2, 3); len];
+        animation.render(Duration::from_millis(elapsed_ms), &mut leds).then_some(leds)
+    }
+
+    #[test]
+    fn blink_on_then_off() {
+        let blink = Animation::Blink { color: Rgb::RED, count: 2, period: PERIOD };
+        assert_eq!(render(&blink, 0, 3), Some(vec![Rgb::RED; 3]));
+        assert_eq!(render(&blink, 499, 3), Some(vec![Rgb::RED; 3]));
+        assert_eq!(render(&blink, 500, 3), Some(vec![Rgb::BLACK; 3]));
+        assert_eq!(render(&blink, 1200, 3), Some(vec![Rgb::RED; 3]));
+    }
+
+    #[test]
+    fn blink_finishes_after_duration() {
+        let blink = Animation::Blink { color: Rgb::RED, count: 2, period: PERIOD };
+        assert_eq!(blink.duration(), Some(Duration::from_secs(2)));
+        assert!(render(&blink, 1999, 1).is_some());
+        assert_eq!(render(&blink, 2000, 1), None);
+        // The LEDs are left alone once finished.
+        let mut leds = [Rgb::WHITE];
+        assert!(!blink.render(Duration::from_secs(5), &mut leds));
+        assert_eq!(leds, [Rgb::WHITE]);
+    }
+
+    #[test]
+    fn blink_overflow_is_forever() {
+        let blink = Animation::Blink { color: Rgb::RED, count: u32::MAX, period: Duration::MAX };
+        assert_eq!(blink.duration(), None);
+        assert!(render(&blink, 0, 1).is_some());
+    }
+
+    #[test]
+    fn chase_position() {
+        let chase = Animation::Chase { color: Rgb::GREEN, background: Rgb::BLACK, period: PERIOD };
+        for (elapsed_ms, lit) in [(0, 0), (260, 1), (510, 2), (760, 3), (1010, 0)] {
+            let leds = render(&chase, elapsed_ms, 4).unwrap();
+            let expected: Vec<Rgb> = (0..4)
+                .map(|idx| if idx == lit { Rgb::GREEN } else { Rgb::BLACK })
+                .collect();
+            assert_eq!(leds, expected, "at {} ms", elapsed_ms);
+        }
+    }
+
+    #[test]
+    fn breathing_endpoints() {
+        let breathing = Animation::Breathing { color: Rgb::WHITE, period: PERIOD };
+        assert_eq!(render(&breathing, 0, 2), Some(vec![Rgb::BLACK; 2]));
+        let brightest = render(&breathing, 500, 1).unwrap()[0];
+        assert!(brightest.r >= 250 && brightest.r == brightest.g && brightest.g == brightest.b);
+        let fading = render(&breathing, 750, 1).unwrap()[0];
+        assert!(fading.r > 0 && fading.r < brightest.r);
+        assert_eq!(breathing.duration(), None);
+    }
+}
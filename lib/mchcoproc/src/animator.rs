@@ -0,0 +1,259 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+
+use crate::animation::Animation;
+use crate::error::{Result, Rp2040Error};
+use crate::mchcoproc::SharedRp2040;
+use crate::ws2812::{Rgb, Ws2812Mode, WS2812_MAX_LEDS};
+
+
+enum Command {
+    Play(Animation),
+    Stop,
+    Notify(Animation),
+}
+
+
+// Handle to the LED task. Dropping it turns the LEDs off and ends the
+// task.
+pub struct LedAnimator {
+    commands: mpsc::Sender<Command>,
+}
+
+impl LedAnimator {
+    pub fn start<I2C: I2c + Send + 'static>(
+        rp2040: SharedRp2040<I2C>,
+        led_count: usize,
+        frame_rate: u32,
+    ) -> Result<Self> {
+        if led_count == 0 || led_count > WS2812_MAX_LEDS {
+            return Err(Rp2040Error::InvalidArgument(
+                format!("LED count {} not in 1..={}", led_count, WS2812_MAX_LEDS)));
+        }
+        if frame_rate == 0 {
+            return Err(Rp2040Error::InvalidArgument("LED frame rate cannot be 0".into()));
+        }
+
+        rp2040.lock().unwrap().set_ws2812_mode(Ws2812Mode::On)?;
+
+        let (commands, command_rx) = mpsc::channel();
+        let frame_interval = Duration::from_secs(1) / frame_rate;
+        thread::Builder::new().name("led-animator".into()).spawn(move || {
+            run_animator(rp2040, led_count, frame_interval, command_rx);
+        }).map_err(|err| Rp2040Error::Task(format!("spawn failed: {}", err)))?;
+
+        Ok(Self { commands })
+    }
+
+    // Replace the running animation (if any).
+    pub fn play(&self, animation: Animation) {
+        self.send(Command::Play(animation));
+    }
+
+    pub fn stop(&self) {
+        self.send(Command::Stop);
+    }
+
+    // Blink count times over whatever is running, then resume it.
+    pub fn notify(&self, color: Rgb, count: u32) {
+        self.send(Command::Notify(Animation::Blink {
+            color,
+            count,
+            period: Duration::from_millis(300),
+        }));
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            log::warn!("LedAnimator: task is gone");
+        }
+    }
+}
+
+
+fn run_animator<I2C: I2c>(
+    rp2040: SharedRp2040<I2C>,
+    led_count: usize,
+    frame_interval: Duration,
+    command_rx: mpsc::Receiver<Command>,
+) {
+    let mut animator = Animator::new(led_count);
+    let mut next_frame = Instant::now();
+
+    loop {
+        match command_rx.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+            Ok(command) => animator.handle(command, Instant::now()),
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let now = Instant::now();
+        if now < next_frame {
+            continue;
+        }
+        next_frame = now + frame_interval;
+
+        let Some(frame) = animator.render(now) else {
+            continue;
+        };
+        if let Err(err) = push_frame(&rp2040, frame) {
+            log::warn!("LedAnimator: could not push frame: {}", err);
+            continue;
+        }
+        animator.shown();
+    }
+
+    if let Err(err) = push_frame(&rp2040, &vec![Rgb::BLACK; led_count]) {
+        log::warn!("LedAnimator: could not turn off LEDs: {}", err);
+    }
+}
+
+
+// What to show when: the running animation, with notifications on top.
+struct Animator {
+    current: Option<(Animation, Instant)>,
+    overlay: Option<(Animation, Instant)>,
+    frame: Vec<Rgb>,
+    shown: Option<Vec<Rgb>>,
+}
+
+impl Animator {
+    fn new(led_count: usize) -> Self {
+        Self { current: None, overlay: None, frame: vec![Rgb::BLACK; led_count], shown: None }
+    }
+
+    fn handle(&mut self, command: Command, now: Instant) {
+        match command {
+            Command::Play(animation) => self.current = Some((animation, now)),
+            Command::Stop => self.current = None,
+            Command::Notify(animation) => self.overlay = Some((animation, now)),
+        }
+    }
+
+    // The frame to push, or None if the LEDs already show it: don't hog
+    // the shared I2C bus with identical frames.
+    fn render(&mut self, now: Instant) -> Option<&[Rgb]> {
+        let mut rendered = false;
+        if let Some((animation, started)) = &self.overlay {
+            rendered = animation.render(now - *started, &mut self.frame);
+            if !rendered {
+                self.overlay = None;
+            }
+        }
+        if !rendered {
+            if let Some((animation, started)) = &self.current {
+                rendered = animation.render(now - *started, &mut self.frame);
+                if !rendered {
+                    self.current = None;
+                }
+            }
+        }
+        if !rendered {
+            self.frame.fill(Rgb::BLACK);
+        }
+
+        if self.shown.as_deref() == Some(&self.frame[..]) {
+            return None;
+        }
+        Some(&self.frame)
+    }
+
+    // The last render() made it to the LEDs.
+    fn shown(&mut self) {
+        self.shown = Some(self.frame.clone());
+    }
+}
+
+fn push_frame<I2C: I2c>(rp2040: &SharedRp2040<I2C>, frame: &[Rgb]) -> Result {
+    let rp = rp2040.lock().unwrap();
+    rp.set_ws2812_leds(frame)?;
+    rp.trigger_ws2812()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::mchcoproc::{Rp2040, Rp2040Sim};
+
+    type SharedSim = Arc<Mutex<Rp2040Sim>>;
+
+    fn sim_rp2040() -> (SharedSim, SharedRp2040<Rp2040Sim>) {
+        let sim = Rp2040Sim::default().shared();
+        let mut rp2040 = Rp2040::new(sim.clone());
+        rp2040.get_firmware_version().unwrap();
+        (sim, Arc::new(Mutex::new(rp2040)))
+    }
+
+    // Render and push the frame at ms, like run_animator() does.
+    fn step(animator: &mut Animator, rp2040: &SharedRp2040<Rp2040Sim>, start: Instant, ms: u64) {
+        if let Some(frame) = animator.render(start + Duration::from_millis(ms)) {
+            push_frame(rp2040, frame).unwrap();
+            animator.shown();
+        }
+    }
+
+    #[test]
+    fn notify_then_resume() {
+        let (sim, rp2040) = sim_rp2040();
+        let mut animator = Animator::new(2);
+        let start = Instant::now();
+        let period = Duration::from_millis(100);
+
+        animator.handle(Command::Play(Animation::Solid(Rgb::GREEN)), start);
+        step(&mut animator, &rp2040, start, 0);
+        let blink = Animation::Blink { color: Rgb::RED, count: 2, period };
+        animator.handle(Command::Notify(blink), start + Duration::from_millis(10));
+        for ms in [10, 60, 110, 160, 210] {
+            step(&mut animator, &rp2040, start, ms);
+        }
+        let green = vec![Rgb::GREEN; 2];
+        let (red, black) = (vec![Rgb::RED; 2], vec![Rgb::BLACK; 2]);
+        assert_eq!(sim.lock().unwrap().take_ws2812_frames(), [
+            green.clone(), red.clone(), black.clone(), red, black, green,
+        ]);
+    }
+
+    #[test]
+    fn stop_and_skip_identical_frames() {
+        let (sim, rp2040) = sim_rp2040();
+        let mut animator = Animator::new(3);
+        let start = Instant::now();
+
+        animator.handle(Command::Play(Animation::Solid(Rgb::BLUE)), start);
+        for ms in [0, 20, 40] {
+            step(&mut animator, &rp2040, start, ms);
+        }
+        animator.handle(Command::Stop, start + Duration::from_millis(50));
+        for ms in [60, 80] {
+            step(&mut animator, &rp2040, start, ms);
+        }
+        assert_eq!(sim.lock().unwrap().take_ws2812_frames(), [vec![Rgb::BLUE; 3], vec![Rgb::BLACK; 3]]);
+    }
+
+    #[test]
+    fn drop_turns_leds_off() {
+        let (sim, rp2040) = sim_rp2040();
+        let wait_for = |frame: Vec<Rgb>| {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            while Instant::now() < deadline {
+                if sim.lock().unwrap().take_ws2812_frames().contains(&frame) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+            false
+        };
+
+        let animator = LedAnimator::start(rp2040, 2, 100).unwrap();
+        animator.play(Animation::Solid(Rgb::WHITE));
+        assert!(wait_for(vec![Rgb::WHITE; 2]));
+        drop(animator);
+        assert!(wait_for(vec![Rgb::BLACK; 2]));
+    }
+}
@@ -0,0 +1,385 @@
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::DynamicSender;
+use embassy_sync::mutex::Mutex;
+use embassy_time::Timer;
+use embedded_hal::digital::Error as _;
+use embedded_hal::i2c::Error as _;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+
+use crate::capabilities::{Capabilities, Capability};
+use crate::error::{Result, Rp2040Error};
+use crate::events::{
+    signal_level,
+    DecodedInputs,
+    InputState,
+    Rp2040InputEvent,
+    Rp2040Signal,
+    Rp2040SignalEvent,
+};
+use crate::ir::IrCommand;
+use crate::mchcoproc::{ADC_SAMPLE_TIME, RP2040_I2C_ADDR};
+use crate::power::PowerStatus;
+use crate::registers::Rp2040Reg;
+
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+// Reads are at least this far apart, so a line stuck low does not keep
+// the bus busy.
+const MIN_READ_INTERVAL: Duration = Duration::from_millis(20);
+
+
+// Async flavour of Rp2040, for apps written as embassy tasks. Owns its
+// bus; share it through an embassy Mutex (see run_inputs()) or an
+// embassy-embedded-hal shared bus.
+pub struct Rp2040Async<I2C> {
+    i2c: I2C,
+    addr: u8,
+    fw_version: u8,
+    capabilities: Capabilities,
+    input_state: InputState,
+    ir_toggle: bool,
+}
+
+impl<I2C: I2c> Rp2040Async<I2C> {
+    pub fn new(i2c: I2C) -> Self {
+        Self {
+            i2c,
+            addr: RP2040_I2C_ADDR,
+            fw_version: 0,
+            capabilities: Capabilities::empty(),
+            input_state: InputState::default(),
+            ir_toggle: false,
+        }
+    }
+
+    pub async fn get_firmware_version(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.read_reg(Rp2040Reg::FwVer, &mut buf).await?;
+        if buf[0] != self.fw_version {
+            self.fw_version = buf[0];
+            self.capabilities = Capabilities::from_fw_version(self.fw_version);
+        }
+        Ok(self.fw_version)
+    }
+
+    // Valid after get_firmware_version() (done by run_inputs()).
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn require(&self, capability: Capability) -> Result {
+        Ok(self.capabilities.require(capability, self.fw_version)?)
+    }
+
+    // Kept up to date by run_inputs().
+    pub fn input_state(&self) -> InputState {
+        self.input_state.clone()
+    }
+
+    // Clears the pending interrupts; leave this to run_inputs() if it
+    // is running.
+    pub async fn read_inputs(&mut self, timestamp: Instant) -> Result<DecodedInputs> {
+        self.require(Capability::Inputs)?;
+        let mut buf = [0u8; 4];
+        self.read_reg(Rp2040Reg::Input1, &mut buf).await?;
+        Ok(self.input_state.update(buf, timestamp))
+    }
+
+    pub async fn read_signal(&mut self, signal: Rp2040Signal) -> Result<bool> {
+        self.require(Capability::Inputs)?;
+        let mut buf = [0u8; 2];
+        self.read_reg(Rp2040Reg::Input1, &mut buf).await?;
+        Ok(signal_level(buf, signal))
+    }
+
+    pub async fn read_power_status(&mut self) -> Result<PowerStatus> {
+        self.require(Capability::Adc)?;
+        self.require(Capability::ChargingState)?;
+        self.write_reg(Rp2040Reg::AdcTrigger, &[1]).await?;
+        Timer::after(ADC_SAMPLE_TIME.try_into().unwrap()).await;
+
+        let vbat = self.read_adc_raw(Rp2040Reg::AdcValueVbatLo).await?;
+        let vusb = self.read_adc_raw(Rp2040Reg::AdcValueVusbLo).await?;
+        let mut charging = [0u8; 1];
+        self.read_reg(Rp2040Reg::ChargingState, &mut charging).await?;
+        Ok(PowerStatus::from_registers(vbat, vusb, charging[0]))
+    }
+
+    pub async fn set_lcd_backlight(&mut self, brightness: u8) -> Result {
+        self.require(Capability::Backlight)?;
+        self.write_reg(Rp2040Reg::LcdBacklight, &[brightness]).await
+    }
+
+    // See Rp2040::send_ir().
+    pub async fn send_ir(&mut self, command: &IrCommand) -> Result {
+        let toggle = !self.ir_toggle;
+        self.write_ir(command, toggle).await?;
+        self.ir_toggle = toggle;
+        Ok(())
+    }
+
+    pub async fn send_ir_repeat(&mut self, command: &IrCommand) -> Result {
+        self.write_ir(command, self.ir_toggle).await
+    }
+
+    async fn write_ir(&mut self, command: &IrCommand, toggle: bool) -> Result {
+        let (capability, buf) = command.trigger_registers(toggle)?;
+        self.require(capability)?;
+        self.write_reg(Rp2040Reg::IrAddressLo, &buf).await
+    }
+
+    async fn read_adc_raw(&mut self, lo_reg: Rp2040Reg) -> Result<u16> {
+        let mut buf = [0u8; 2];
+        self.read_reg(lo_reg, &mut buf).await?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    async fn read_reg(&mut self, reg: impl Into<u8>, buf: &mut [u8]) -> Result {
+        let reg = reg.into();
+        self.i2c
+            .write_read(self.addr, &[reg], buf).await
+            .map_err(|err| Rp2040Error::I2c { op: "read", addr: self.addr, reg, kind: err.kind() })
+    }
+
+    async fn write_reg(&mut self, reg: impl Into<u8>, data: &[u8]) -> Result {
+        let mut out = Vec::with_capacity(1 + data.len());
+        out.push(reg.into());
+        out.extend_from_slice(data);
+        log::debug!("write_reg: {:?}", out);
+        self.i2c
+            .write(self.addr, &out).await
+            .map_err(|err| Rp2040Error::I2c { op: "write", addr: self.addr, reg: out[0], kind: err.kind() })
+    }
+}
+
+
+// The async input task: reads the inputs whenever the interrupt line
+// (GPIO34) is low and sends the events. Waiting for the level instead
+// of an edge means nothing is lost between reads, but a line stuck low
+// turns it into polling every MIN_READ_INTERVAL. Failed reads are
+// retried with backoff; only an unusable line ends it.
+//
+// Signals are dropped if signals is None. The channels must be drained:
+// a full channel stalls the input reads.
+pub async fn run_inputs<M: RawMutex, I2C: I2c, P: Wait>(
+    rp2040: &Mutex<M, Rp2040Async<I2C>>,
+    mut line: P,
+    inputs: DynamicSender<'_, Rp2040InputEvent>,
+    signals: Option<DynamicSender<'_, Rp2040SignalEvent>>,
+) -> Result<Infallible> {
+    {
+        let mut rp = rp2040.lock().await;
+        rp.get_firmware_version().await?;
+        rp.require(Capability::Inputs)?;
+    }
+
+    let mut backoff: Option<Duration> = None;
+    loop {
+        let timestamp = Instant::now();
+        // Hold the lock only for the read, not while sending.
+        let result = rp2040.lock().await.read_inputs(timestamp).await;
+        match result {
+            Ok(decoded) => {
+                if backoff.take().is_some() {
+                    log::info!("RP2040 inputs readable again");
+                }
+                for ev in decoded.inputs {
+                    inputs.send(ev).await;
+                }
+                if let Some(signals) = &signals {
+                    for ev in decoded.signals {
+                        signals.send(ev).await;
+                    }
+                }
+            }
+            Err(err) => {
+                let delay = backoff.map_or(MIN_BACKOFF, |backoff| backoff * 2).min(MAX_BACKOFF);
+                backoff = Some(delay);
+                log::warn!("Could not read RP2040 inputs, retry in {:?}: {}", delay, err);
+                Timer::after(delay.try_into().unwrap()).await;
+                continue;
+            }
+        }
+
+        line.wait_for_low().await
+            .map_err(|err| Rp2040Error::Task(format!("interrupt line: {:?}", err.kind())))?;
+        // The read releases the line, so low again this soon means it is
+        // stuck (or the inputs are very busy).
+        if let Some(wait) = MIN_READ_INTERVAL.checked_sub(timestamp.elapsed()) {
+            Timer::after(wait.try_into().unwrap()).await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future::Future;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::channel::Channel;
+    use embassy_time::with_timeout;
+    use embedded_hal::i2c::{ErrorKind, ErrorType, Operation};
+
+    use crate::events::Rp2040Input;
+    use crate::mchcoproc::{ChargingState, Rp2040Sim, SimAsyncI2c, SimInterruptLine};
+
+    const EVENT_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_millis(500);
+
+    type Events = Channel<NoopRawMutex, Rp2040InputEvent, 8>;
+    type SharedSim = Arc<std::sync::Mutex<Rp2040Sim>>;
+
+    // Counts the transfers to the sim.
+    struct CountingI2c {
+        sim: SimAsyncI2c,
+        transfers: Arc<AtomicUsize>,
+    }
+
+    impl ErrorType for CountingI2c {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for CountingI2c {
+        async fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> core::result::Result<(), ErrorKind> {
+            self.transfers.fetch_add(1, Ordering::Relaxed);
+            self.sim.transaction(address, operations).await
+        }
+    }
+
+    // An interrupt line that never lets go.
+    struct StuckLow;
+
+    impl embedded_hal::digital::ErrorType for StuckLow {
+        type Error = Infallible;
+    }
+
+    impl Wait for StuckLow {
+        async fn wait_for_high(&mut self) -> core::result::Result<(), Infallible> {
+            std::future::pending().await
+        }
+
+        async fn wait_for_low(&mut self) -> core::result::Result<(), Infallible> {
+            Ok(())
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> core::result::Result<(), Infallible> {
+            std::future::pending().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> core::result::Result<(), Infallible> {
+            std::future::pending().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> core::result::Result<(), Infallible> {
+            std::future::pending().await
+        }
+    }
+
+    fn counting_rp2040(sim: &SharedSim) -> (Arc<AtomicUsize>, Rp2040Async<CountingI2c>) {
+        let transfers = Arc::new(AtomicUsize::new(0));
+        let i2c = CountingI2c { sim: SimAsyncI2c::new(sim.clone()), transfers: transfers.clone() };
+        (transfers, Rp2040Async::new(i2c))
+    }
+
+    // Run test with inputs (run_inputs()) next to it.
+    fn run_with_inputs(inputs: impl Future<Output = Result<Infallible>>, test: impl Future<Output = ()>) {
+        if let Either::First(result) = block_on(select(inputs, test)) {
+            panic!("run_inputs() ended: {:?}", result);
+        }
+    }
+
+    async fn next_event(events: &Events) -> (Rp2040Input, bool) {
+        let event = with_timeout(EVENT_TIMEOUT, events.receive()).await.expect("no input event");
+        (event.input, event.is_released)
+    }
+
+    #[test]
+    fn press_and_release() {
+        let sim = Rp2040Sim::default().shared();
+        let rp2040 = Mutex::<NoopRawMutex, _>::new(Rp2040Async::new(SimAsyncI2c::new(sim.clone())));
+        let events = Events::new();
+        let line = SimInterruptLine::new(sim.clone());
+
+        run_with_inputs(run_inputs(&rp2040, line, events.dyn_sender(), None), async {
+            sim.lock().unwrap().press(Rp2040Input::ButtonStart);
+            assert_eq!(next_event(&events).await, (Rp2040Input::ButtonStart, false));
+            assert!(rp2040.lock().await.input_state().is_pressed(Rp2040Input::ButtonStart));
+            sim.lock().unwrap().release(Rp2040Input::ButtonStart);
+            assert_eq!(next_event(&events).await, (Rp2040Input::ButtonStart, true));
+        });
+    }
+
+    #[test]
+    fn backoff_while_unreadable() {
+        let sim = Rp2040Sim::default().shared();
+        let (transfers, rp2040) = counting_rp2040(&sim);
+        let rp2040 = Mutex::<NoopRawMutex, _>::new(rp2040);
+        let events = Events::new();
+        let line = SimInterruptLine::new(sim.clone());
+
+        run_with_inputs(run_inputs(&rp2040, line, events.dyn_sender(), None), async {
+            sim.lock().unwrap().press(Rp2040Input::ButtonAccept);
+            assert_eq!(next_event(&events).await, (Rp2040Input::ButtonAccept, false));
+
+            sim.lock().unwrap().set_offline(true);
+            sim.lock().unwrap().release(Rp2040Input::ButtonAccept);
+            let before = transfers.load(Ordering::Relaxed);
+            Timer::after_millis(300).await;
+            // Retries after 10, 20, 40, 80 and 160 ms, not a busy loop.
+            let failed = transfers.load(Ordering::Relaxed) - before;
+            assert!((3..=6).contains(&failed), "{} reads", failed);
+
+            sim.lock().unwrap().set_offline(false);
+            assert_eq!(next_event(&events).await, (Rp2040Input::ButtonAccept, true));
+        });
+    }
+
+    #[test]
+    fn stuck_line_is_rate_limited() {
+        let sim = Rp2040Sim::default().shared();
+        let (transfers, rp2040) = counting_rp2040(&sim);
+        let rp2040 = Mutex::<NoopRawMutex, _>::new(rp2040);
+        let events = Events::new();
+
+        run_with_inputs(run_inputs(&rp2040, StuckLow, events.dyn_sender(), None), async {
+            Timer::after_millis(200).await;
+            // One read per MIN_READ_INTERVAL (20 ms).
+            let reads = transfers.load(Ordering::Relaxed);
+            assert!((5..=12).contains(&reads), "{} reads", reads);
+
+            sim.lock().unwrap().press(Rp2040Input::ButtonBack);
+            assert_eq!(next_event(&events).await, (Rp2040Input::ButtonBack, false));
+        });
+    }
+
+    #[test]
+    fn power_status() {
+        let sim = Rp2040Sim::default().shared();
+        sim.lock().unwrap().set_battery_voltage(3.7);
+        sim.lock().unwrap().set_usb_voltage(5.0);
+        sim.lock().unwrap().set_charging(true);
+        let mut rp2040 = Rp2040Async::new(SimAsyncI2c::new(sim));
+        let status = block_on(async {
+            rp2040.get_firmware_version().await.unwrap();
+            rp2040.read_power_status().await.unwrap()
+        });
+        assert!((status.battery_voltage - 3.7).abs() < 0.002, "{}", status.battery_voltage);
+        assert!((status.usb_voltage - 5.0).abs() < 0.002, "{}", status.usb_voltage);
+        assert_eq!(status.charging_state, ChargingState::Charging);
+    }
+}
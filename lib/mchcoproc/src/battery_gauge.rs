@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::power::ChargingState;
+
+
+// Samples in the moving average. At one sample per second this smooths
+// out the ADC noise without lagging too much.
+const AVERAGE_SAMPLES: usize = 8;
+// The reported percentage only moves against the (dis)charge direction
+// when the estimate is this far off.
+const HYSTERESIS_PERCENT: f32 = 5.0;
+// Charge current through the cell's internal resistance lifts the
+// measured voltage while charging.
+const CHARGING_VOLTAGE_OFFSET: f32 = 0.10;
+// Discharge rate is computed over this window.
+const RATE_WINDOW: Duration = Duration::from_secs(10 * 60);
+const RATE_MIN_WINDOW: Duration = Duration::from_secs(2 * 60);
+
+// Typical resting LiPo voltage vs. remaining capacity, high to low.
+const LIPO_CURVE: [(f32, f32); 21] = [
+    (4.20, 100.0),
+    (4.15, 95.0),
+    (4.11, 90.0),
+    (4.08, 85.0),
+    (4.02, 80.0),
+    (3.98, 75.0),
+    (3.95, 70.0),
+    (3.91, 65.0),
+    (3.87, 60.0),
+    (3.85, 55.0),
+    (3.84, 50.0),
+    (3.82, 45.0),
+    (3.80, 40.0),
+    (3.79, 35.0),
+    (3.77, 30.0),
+    (3.75, 25.0),
+    (3.73, 20.0),
+    (3.71, 15.0),
+    (3.69, 10.0),
+    (3.61, 5.0),
+    (3.27, 0.0),
+];
+
+
+// Remaining capacity (0..=100) for a resting cell voltage.
+pub fn lipo_percent(voltage: f32) -> f32 {
+    let (v_max, p_max) = LIPO_CURVE[0];
+    let (v_min, p_min) = LIPO_CURVE[LIPO_CURVE.len() - 1];
+    if voltage >= v_max {
+        return p_max;
+    }
+    if voltage <= v_min {
+        return p_min;
+    }
+    for pair in LIPO_CURVE.windows(2) {
+        let (v_hi, p_hi) = pair[0];
+        let (v_lo, p_lo) = pair[1];
+        if voltage >= v_lo {
+            return p_lo + (p_hi - p_lo) * (voltage - v_lo) / (v_hi - v_lo);
+        }
+    }
+    p_min
+}
+
+
+// Turns noisy VBAT samples into a steady percentage. Feed it a sample
+// every second or so.
+pub struct BatteryGauge {
+    samples: VecDeque<f32>,
+    state: Option<ChargingState>,
+    percent: Option<f32>,
+    history: VecDeque<(Instant, f32)>,
+}
+
+impl BatteryGauge {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(AVERAGE_SAMPLES),
+            state: None,
+            percent: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn add_sample(&mut self, voltage: f32, state: ChargingState, now: Instant) {
+        if self.state != Some(state) {
+            // The voltage jumps when USB comes or goes; start over.
+            self.samples.clear();
+            self.history.clear();
+            self.state = Some(state);
+        }
+
+        if self.samples.len() == AVERAGE_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(voltage);
+        let average = self.samples.iter().sum::<f32>() / (self.samples.len() as f32);
+
+        let estimate = match state {
+            ChargingState::Discharging => lipo_percent(average),
+            ChargingState::Charging => lipo_percent(average - CHARGING_VOLTAGE_OFFSET),
+            ChargingState::Charged => 100.0,
+        };
+
+        let percent = match self.percent {
+            None => estimate,
+            // Don't go up while discharging or down while charging,
+            // unless we were clearly wrong.
+            Some(prev) => match state {
+                ChargingState::Discharging if estimate > prev && estimate - prev < HYSTERESIS_PERCENT => prev,
+                ChargingState::Charging if estimate < prev && prev - estimate < HYSTERESIS_PERCENT => prev,
+                _ => estimate,
+            },
+        };
+        self.percent = Some(percent);
+
+        self.history.push_back((now, percent));
+        while let Some(&(at, _)) = self.history.front() {
+            if now.saturating_duration_since(at) <= RATE_WINDOW {
+                break;
+            }
+            self.history.pop_front();
+        }
+    }
+
+    pub fn percent(&self) -> Option<u8> {
+        self.percent.map(|p| p.round().clamp(0.0, 100.0) as u8)
+    }
+
+    pub fn charging_state(&self) -> Option<ChargingState> {
+        self.state
+    }
+
+    // Only known while discharging, and after watching for a while.
+    pub fn time_to_empty(&self) -> Option<Duration> {
+        if self.state != Some(ChargingState::Discharging) {
+            return None;
+        }
+        let &(first_at, first) = self.history.front()?;
+        let &(last_at, last) = self.history.back()?;
+        let elapsed = last_at.saturating_duration_since(first_at);
+        if elapsed < RATE_MIN_WINDOW || last >= first {
+            return None;
+        }
+        let percent_per_sec = (first - last) / elapsed.as_secs_f32();
+        Some(Duration::from_secs_f32(last / percent_per_sec))
+    }
+}
+
+impl Default for BatteryGauge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 0.01, "{} != {}", actual, expected);
+    }
+
+    fn gauge_with(samples: &[(f32, ChargingState)]) -> BatteryGauge {
+        let mut gauge = BatteryGauge::new();
+        let start = Instant::now();
+        for (idx, &(voltage, state)) in samples.iter().enumerate() {
+            gauge.add_sample(voltage, state, start + Duration::from_secs(idx as u64));
+        }
+        gauge
+    }
+
+    #[test]
+    fn curve_interpolates_and_clamps() {
+        assert_near(lipo_percent(4.20), 100.0);
+        assert_near(lipo_percent(4.35), 100.0);
+        assert_near(lipo_percent(3.27), 0.0);
+        assert_near(lipo_percent(3.00), 0.0);
+        assert_near(lipo_percent(3.95), 70.0);
+        assert_near(lipo_percent(3.83), 47.5);
+        assert_near(lipo_percent(3.44), 2.5);
+    }
+
+    #[test]
+    fn hysteresis_while_discharging() {
+        use ChargingState::Discharging;
+        // Average 3.8425 V, 51.25%: a small rise is ignored.
+        let gauge = gauge_with(&[(3.84, Discharging), (3.845, Discharging)]);
+        assert_eq!(gauge.percent(), Some(50));
+        // Going down is always fine.
+        let gauge = gauge_with(&[(3.84, Discharging), (3.835, Discharging)]);
+        assert_eq!(gauge.percent(), Some(49));
+        // Average 3.91 V, 65%: clearly wrong before, so go up.
+        let gauge = gauge_with(&[(3.84, Discharging), (3.98, Discharging)]);
+        assert_eq!(gauge.percent(), Some(65));
+    }
+
+    #[test]
+    fn hysteresis_while_charging() {
+        use ChargingState::Charging;
+        // Charging reads 0.10 V high. Average 3.8375 V, 48.75%: a small
+        // drop is ignored.
+        let gauge = gauge_with(&[(3.94, Charging), (3.935, Charging)]);
+        assert_eq!(gauge.percent(), Some(50));
+        let gauge = gauge_with(&[(3.94, Charging), (3.945, Charging)]);
+        assert_eq!(gauge.percent(), Some(51));
+        // Average 3.75 V, 25%.
+        let gauge = gauge_with(&[(3.94, Charging), (3.76, Charging)]);
+        assert_eq!(gauge.percent(), Some(25));
+    }
+
+    #[test]
+    fn state_change_starts_over() {
+        use ChargingState::{Charged, Charging, Discharging};
+        let gauge = gauge_with(&[(4.20, Discharging), (4.20, Discharging), (3.83, Charging)]);
+        // Only the 3.83 V sample counts, not the average with 4.20 V.
+        assert_eq!(gauge.percent(), Some(20));
+        assert_eq!(gauge.charging_state(), Some(Charging));
+
+        let gauge = gauge_with(&[(3.73, Discharging), (4.10, Charged)]);
+        assert_eq!(gauge.percent(), Some(100));
+        assert_eq!(gauge.time_to_empty(), None);
+    }
+
+    #[test]
+    fn time_to_empty_after_min_window() {
+        let mut gauge = BatteryGauge::new();
+        let start = Instant::now();
+        let mut add = |secs: u64, voltage: f32| {
+            gauge.add_sample(voltage, ChargingState::Discharging, start + Duration::from_secs(secs));
+            gauge.time_to_empty()
+        };
+        // The averages are 3.84, 3.835 and 3.83 V: 50, 48.75 and 47.5%.
+        assert_eq!(add(0, 3.84), None);
+        assert_eq!(add(60, 3.83), None);
+        // 2.5% in 2 minutes, so 47.5% lasts 38 minutes.
+        let remaining = add(120, 3.82).unwrap();
+        assert!(remaining.abs_diff(Duration::from_secs(38 * 60)) < Duration::from_secs(30), "{:?}", remaining);
+    }
+}
@@ -0,0 +1,477 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+#[cfg(feature = "esp-idf")]
+use esp_idf_svc::hal::delay::TICK_RATE_HZ;
+#[cfg(feature = "esp-idf")]
+use esp_idf_svc::hal::uart::UartDriver;
+
+use crate::error::{Result, Rp2040Error};
+use crate::mchcoproc::{SharedRp2040, BOOTLOADER_FW_VERSION};
+
+
+// Firmware is not sent over I2C: the RP2040 bootloader speaks the
+// rp2040-serial-bootloader protocol on the UART between the ESP32 and the
+// RP2040. Every command is 4 ASCII bytes followed by little endian u32
+// arguments; replies start with "OKOK" (or "ERR!").
+const CMD_SYNC: &[u8; 4] = b"SYNC";
+const CMD_INFO: &[u8; 4] = b"INFO";
+const CMD_ERASE: &[u8; 4] = b"ERAS";
+const CMD_WRITE: &[u8; 4] = b"WRIT";
+const CMD_SEAL: &[u8; 4] = b"SEAL";
+const CMD_GO: &[u8; 4] = b"GOGO";
+const CMD_CRC: &[u8; 4] = b"CRCC";
+
+const RSP_SYNC: &[u8; 4] = b"PICO";
+const RSP_OK: &[u8; 4] = b"OKOK";
+
+#[cfg(feature = "esp-idf")]
+const UART_TIMEOUT: u32 = TICK_RATE_HZ; // exactly 1 second
+const MODE_SWITCH_TIMEOUT: Duration = Duration::from_secs(5);
+const MODE_SWITCH_POLL: Duration = Duration::from_millis(100);
+
+
+// The byte pipe to the bootloader. Implemented for the UART driver;
+// anything else (e.g. a test double) works too.
+pub trait BootloaderPort {
+    fn write_all(&mut self, data: &[u8]) -> Result;
+    // Fails if not all of buf arrives in time.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result;
+}
+
+#[cfg(feature = "esp-idf")]
+impl BootloaderPort for UartDriver<'_> {
+    fn write_all(&mut self, mut data: &[u8]) -> Result {
+        while !data.is_empty() {
+            let n = self.write(data).map_err(Rp2040Error::Uart)?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result {
+        while !buf.is_empty() {
+            let n = self.read(buf, UART_TIMEOUT).map_err(Rp2040Error::Uart)?;
+            if n == 0 {
+                return Err(Rp2040Error::Timeout(
+                    format!("bootloader: waiting for {} more bytes", buf.len())));
+            }
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BootloaderInfo {
+    pub flash_start: u32,
+    pub flash_size: u32,
+    pub erase_size: u32,
+    pub write_size: u32,
+    pub max_data_len: u32,
+}
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UpdateStage {
+    Erasing,
+    Writing,
+    Verifying,
+}
+
+
+pub struct FirmwareUpdater<P: BootloaderPort> {
+    port: P,
+    info: BootloaderInfo,
+}
+
+impl<P: BootloaderPort> FirmwareUpdater<P> {
+    // The RP2040 must already be in its bootloader.
+    pub fn connect(mut port: P) -> Result<Self> {
+        port.write_all(CMD_SYNC)?;
+        let mut rsp = [0u8; 4];
+        port.read_exact(&mut rsp)?;
+        if &rsp != RSP_SYNC {
+            return Err(Rp2040Error::Bootloader(format!("bad sync response {:?}", rsp)));
+        }
+
+        let mut updater = Self { port, info: BootloaderInfo::default() };
+        let info = updater.command(CMD_INFO, &[], &[], 5)?;
+        updater.info = BootloaderInfo {
+            flash_start: info[0],
+            flash_size: info[1],
+            erase_size: info[2],
+            write_size: info[3],
+            max_data_len: info[4],
+        };
+        if updater.info.erase_size == 0 || updater.info.write_size == 0
+                || updater.info.max_data_len < updater.info.write_size {
+            return Err(Rp2040Error::Bootloader(format!("unusable info {:?}", updater.info)));
+        }
+        log::info!("bootloader: {:?}", updater.info);
+        Ok(updater)
+    }
+
+    pub fn info(&self) -> &BootloaderInfo {
+        &self.info
+    }
+
+    // Erase, write and verify image at the start of the application
+    // flash, then seal it so the bootloader will start it. progress gets
+    // the stage and bytes done out of total for that stage.
+    pub fn flash<F>(&mut self, image: &[u8], mut progress: F) -> Result
+    where
+        F: FnMut(UpdateStage, usize, usize),
+    {
+        let info = self.info;
+        let padded_len = align_up(image.len(), info.write_size as usize);
+        if image.is_empty() || padded_len > info.flash_size as usize {
+            return Err(Rp2040Error::InvalidArgument(format!(
+                "image of {} bytes does not fit in {} bytes", image.len(), info.flash_size)));
+        }
+        let mut padded = image.to_vec();
+        padded.resize(padded_len, 0xFF);
+
+        let erase_len = align_up(padded_len, info.erase_size as usize);
+        let erase_step = info.erase_size as usize;
+        for offset in (0..erase_len).step_by(erase_step) {
+            progress(UpdateStage::Erasing, offset, erase_len);
+            let addr = info.flash_start + offset as u32;
+            self.command(CMD_ERASE, &[addr, info.erase_size], &[], 0)?;
+        }
+        progress(UpdateStage::Erasing, erase_len, erase_len);
+
+        let chunk_len = align_down(info.max_data_len as usize, info.write_size as usize);
+        for (idx, chunk) in padded.chunks(chunk_len).enumerate() {
+            let offset = idx * chunk_len;
+            progress(UpdateStage::Writing, offset, padded_len);
+            let addr = info.flash_start + offset as u32;
+            let rsp = self.command(CMD_WRITE, &[addr, chunk.len() as u32], chunk, 1)?;
+            if rsp[0] != crc32(chunk) {
+                return Err(Rp2040Error::Bootloader(format!("crc mismatch writing {:#010X}", addr)));
+            }
+        }
+        progress(UpdateStage::Writing, padded_len, padded_len);
+
+        progress(UpdateStage::Verifying, 0, padded_len);
+        let crc = crc32(&padded);
+        let rsp = self.command(CMD_CRC, &[info.flash_start, padded_len as u32], &[], 1)?;
+        if rsp[0] != crc {
+            return Err(Rp2040Error::Bootloader(
+                format!("image crc {:#010X}, flash has {:#010X}", crc, rsp[0])));
+        }
+        self.command(CMD_SEAL, &[info.flash_start, padded_len as u32, crc], &[], 0)?;
+        progress(UpdateStage::Verifying, padded_len, padded_len);
+        Ok(())
+    }
+
+    // Start the application. There is no reply.
+    pub fn reboot(mut self) -> Result {
+        let mut out = CMD_GO.to_vec();
+        out.extend_from_slice(&self.info.flash_start.to_le_bytes());
+        self.port.write_all(&out)
+    }
+
+    fn command(
+        &mut self,
+        cmd: &[u8; 4],
+        args: &[u32],
+        data: &[u8],
+        reply_words: usize,
+    ) -> Result<Vec<u32>> {
+        let mut out = Vec::with_capacity(4 + 4 * args.len() + data.len());
+        out.extend_from_slice(cmd);
+        for arg in args {
+            out.extend_from_slice(&arg.to_le_bytes());
+        }
+        out.extend_from_slice(data);
+        self.port.write_all(&out)?;
+
+        let mut rsp = [0u8; 4];
+        self.port.read_exact(&mut rsp)?;
+        if &rsp != RSP_OK {
+            return Err(Rp2040Error::Bootloader(format!("{} failed: {:?}",
+                String::from_utf8_lossy(cmd), String::from_utf8_lossy(&rsp))));
+        }
+
+        let mut reply = vec![0u8; 4 * reply_words];
+        self.port.read_exact(&mut reply)?;
+        Ok(reply.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect())
+    }
+}
+
+
+// Complete update: switch the RP2040 to its bootloader over I2C, flash
+// image over port, and wait for the new application to come up. Returns
+// the new firmware version.
+pub fn update_firmware<I2C, P, F>(
+    rp2040: &SharedRp2040<I2C>,
+    port: P,
+    image: &[u8],
+    progress: F,
+) -> Result<u8>
+where
+    I2C: I2c,
+    P: BootloaderPort,
+    F: FnMut(UpdateStage, usize, usize),
+{
+    let version = rp2040.lock().unwrap().get_firmware_version()?;
+    if version != BOOTLOADER_FW_VERSION {
+        rp2040.lock().unwrap().enter_bootloader()?;
+        wait_for_mode(rp2040, true)?;
+    }
+    {
+        let rp = rp2040.lock().unwrap();
+        log::info!("bootloader: version {:#04X}, state {:#04X}",
+                   rp.get_bootloader_version()?, rp.get_bootloader_state()?);
+    }
+
+    let mut updater = FirmwareUpdater::connect(port)?;
+    updater.flash(image, progress)?;
+    updater.reboot()?;
+
+    wait_for_mode(rp2040, false)
+}
+
+// Poll the firmware version until the RP2040 is (or is no longer) in its
+// bootloader. While it reboots it does not answer, so errors are
+// expected for a while.
+fn wait_for_mode<I2C: I2c>(rp2040: &SharedRp2040<I2C>, bootloader: bool) -> Result<u8> {
+    let start = Instant::now();
+    loop {
+        thread::sleep(MODE_SWITCH_POLL);
+        if let Ok(version) = rp2040.lock().unwrap().get_firmware_version() {
+            if (version == BOOTLOADER_FW_VERSION) == bootloader {
+                return Ok(version);
+            }
+        }
+        if start.elapsed() > MODE_SWITCH_TIMEOUT {
+            return Err(Rp2040Error::Timeout(format!("RP2040 did not switch to {} mode",
+                if bootloader { "bootloader" } else { "application" })));
+        }
+    }
+}
+
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+fn align_down(value: usize, align: usize) -> usize {
+    (value / align) * align
+}
+
+// CRC-32 (IEEE), as the bootloader computes it with the DMA sniffer.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    use crate::mchcoproc::{Rp2040, Rp2040Sim};
+
+    const INFO: [u32; 5] = [0x1001_0000, 0x1000, 0x100, 0x10, 40];
+
+    // Answers from a script of reply bytes and records everything
+    // written. With app set, GOGO starts that firmware on the sim.
+    #[derive(Default)]
+    struct ScriptedPort {
+        replies: VecDeque<u8>,
+        written: Vec<u8>,
+        app: Option<(Arc<Mutex<Rp2040Sim>>, u8)>,
+    }
+
+    impl ScriptedPort {
+        fn connected() -> Self {
+            Self::default().reply(RSP_SYNC).ok(&INFO)
+        }
+
+        fn reply(mut self, data: &[u8]) -> Self {
+            self.replies.extend(data);
+            self
+        }
+
+        fn ok(self, words: &[u32]) -> Self {
+            let words: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            self.reply(RSP_OK).reply(&words)
+        }
+
+        // Replies for flashing image with INFO, with verify_crc as the
+        // CRCC result.
+        fn flashed(self, image: &[u8], verify_crc: u32) -> Self {
+            let mut padded = image.to_vec();
+            padded.resize(align_up(image.len(), INFO[3] as usize), 0xFF);
+            let mut port = self.ok(&[]);
+            for chunk in padded.chunks(32) {
+                port = port.ok(&[crc32(chunk)]);
+            }
+            port.ok(&[verify_crc]).ok(&[])
+        }
+
+        // The commands written, split into name, arguments and data.
+        fn commands(&self) -> Vec<(String, Vec<u32>, Vec<u8>)> {
+            let mut commands = Vec::new();
+            let mut rest = &self.written[..];
+            while !rest.is_empty() {
+                let name = String::from_utf8(rest[..4].to_vec()).unwrap();
+                let arg_count = match name.as_str() {
+                    "SYNC" | "INFO" => 0,
+                    "GOGO" => 1,
+                    "ERAS" | "WRIT" | "CRCC" => 2,
+                    "SEAL" => 3,
+                    _ => panic!("unknown command {}", name),
+                };
+                let args: Vec<u32> = rest[4..4 + 4 * arg_count]
+                    .chunks_exact(4)
+                    .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+                    .collect();
+                rest = &rest[4 + 4 * arg_count..];
+                let data_len = if name == "WRIT" { args[1] as usize } else { 0 };
+                commands.push((name, args, rest[..data_len].to_vec()));
+                rest = &rest[data_len..];
+            }
+            commands
+        }
+
+        fn names(&self) -> Vec<String> {
+            self.commands().into_iter().map(|(name, _, _)| name).collect()
+        }
+    }
+
+    impl BootloaderPort for &mut ScriptedPort {
+        fn write_all(&mut self, data: &[u8]) -> Result {
+            self.written.extend_from_slice(data);
+            if data.starts_with(CMD_GO) {
+                if let Some((sim, fw_version)) = &self.app {
+                    sim.lock().unwrap().leave_bootloader(*fw_version);
+                }
+            }
+            Ok(())
+        }
+
+        fn read_exact(&mut self, buf: &mut [u8]) -> Result {
+            if self.replies.len() < buf.len() {
+                return Err(Rp2040Error::Timeout("no reply".to_string()));
+            }
+            for byte in buf {
+                *byte = self.replies.pop_front().unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn connect_needs_pico() {
+        let mut port = ScriptedPort::default();
+        assert!(matches!(FirmwareUpdater::connect(&mut port), Err(Rp2040Error::Timeout(_))));
+        assert_eq!(port.names(), ["SYNC"]);
+
+        let mut port = ScriptedPort::default().reply(b"ERR!");
+        assert!(matches!(FirmwareUpdater::connect(&mut port), Err(Rp2040Error::Bootloader(_))));
+    }
+
+    #[test]
+    fn connect_rejects_bad_info() {
+        for info in [
+            [0x1001_0000, 0x1000, 0, 0x10, 40],
+            [0x1001_0000, 0x1000, 0x100, 0, 40],
+            [0x1001_0000, 0x1000, 0x100, 0x10, 8],
+        ] {
+            let mut port = ScriptedPort::default().reply(RSP_SYNC).ok(&info);
+            assert!(matches!(FirmwareUpdater::connect(&mut port), Err(Rp2040Error::Bootloader(_))));
+        }
+
+        let mut port = ScriptedPort::connected();
+        let updater = FirmwareUpdater::connect(&mut port).unwrap();
+        let info = updater.info();
+        assert_eq!(
+            [info.flash_start, info.flash_size, info.erase_size, info.write_size, info.max_data_len],
+            INFO);
+    }
+
+    #[test]
+    fn flash_chunks_and_pads() {
+        let image = image(70);
+        let mut padded = image.clone();
+        padded.resize(80, 0xFF);
+        let mut port = ScriptedPort::connected().flashed(&image, crc32(&padded));
+
+        let mut updater = FirmwareUpdater::connect(&mut port).unwrap();
+        let mut stages = Vec::new();
+        updater.flash(&image, |stage, done, total| stages.push((stage, done, total))).unwrap();
+        updater.reboot().unwrap();
+
+        let start = INFO[0];
+        assert_eq!(port.commands(), [
+            ("SYNC".to_string(), vec![], vec![]),
+            ("INFO".to_string(), vec![], vec![]),
+            ("ERAS".to_string(), vec![start, 0x100], vec![]),
+            ("WRIT".to_string(), vec![start, 32], padded[..32].to_vec()),
+            ("WRIT".to_string(), vec![start + 32, 32], padded[32..64].to_vec()),
+            ("WRIT".to_string(), vec![start + 64, 16], padded[64..].to_vec()),
+            ("CRCC".to_string(), vec![start, 80], vec![]),
+            ("SEAL".to_string(), vec![start, 80, crc32(&padded)], vec![]),
+            ("GOGO".to_string(), vec![start], vec![]),
+        ]);
+        assert_eq!(stages.last(), Some(&(UpdateStage::Verifying, 80, 80)));
+    }
+
+    #[test]
+    fn flash_stops_on_crc_mismatch() {
+        let image = image(70);
+        let mut port = ScriptedPort::connected().ok(&[]).ok(&[!crc32(&image[..32])]);
+        let mut updater = FirmwareUpdater::connect(&mut port).unwrap();
+        assert!(matches!(updater.flash(&image, |_, _, _| ()), Err(Rp2040Error::Bootloader(_))));
+        assert_eq!(port.names(), ["SYNC", "INFO", "ERAS", "WRIT"]);
+
+        let mut port = ScriptedPort::connected().flashed(&image, 0x1234_5678);
+        let mut updater = FirmwareUpdater::connect(&mut port).unwrap();
+        assert!(matches!(updater.flash(&image, |_, _, _| ()), Err(Rp2040Error::Bootloader(_))));
+        assert_eq!(port.names(), ["SYNC", "INFO", "ERAS", "WRIT", "WRIT", "WRIT", "CRCC"]);
+    }
+
+    #[test]
+    fn update_seals_and_starts_after_verify() {
+        let sim = Rp2040Sim::default().shared();
+        let rp2040 = Arc::new(Mutex::new(Rp2040::new(sim.clone())));
+        let image = image(64);
+        let mut port = ScriptedPort::connected().flashed(&image, crc32(&image));
+        port.app = Some((sim.clone(), 0x03));
+
+        assert_eq!(update_firmware(&rp2040, &mut port, &image, |_, _, _| ()).unwrap(), 0x03);
+        assert!(!sim.lock().unwrap().is_in_bootloader());
+        assert_eq!(port.names(), ["SYNC", "INFO", "ERAS", "WRIT", "WRIT", "CRCC", "SEAL", "GOGO"]);
+    }
+
+    #[test]
+    fn update_does_not_start_unverified_image() {
+        let sim = Rp2040Sim::default().shared();
+        let rp2040 = Arc::new(Mutex::new(Rp2040::new(sim.clone())));
+        let image = image(64);
+        let mut port = ScriptedPort::connected().flashed(&image, !crc32(&image));
+        port.app = Some((sim.clone(), 0x03));
+
+        assert!(update_firmware(&rp2040, &mut port, &image, |_, _, _| ()).is_err());
+        assert!(sim.lock().unwrap().is_in_bootloader());
+        assert_eq!(port.names(), ["SYNC", "INFO", "ERAS", "WRIT", "WRIT", "CRCC"]);
+    }
+}
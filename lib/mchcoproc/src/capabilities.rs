@@ -0,0 +1,167 @@
+use std::fmt;
+
+use strum::{EnumIter, IntoEnumIterator};
+
+use crate::mchcoproc::BOOTLOADER_FW_VERSION;
+
+
+// Features of the RP2040 firmware that the driver knows how to use.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, EnumIter, Eq, PartialEq)]
+pub enum Capability {
+    Inputs = 0,
+    Gpio,
+    Backlight,
+    EnterBootloader,
+    Adc,           // VBAT, VUSB and explicit AdcTrigger
+    Temperature,
+    ChargingState,
+    Uid,
+    Scratch,
+    Ws2812,
+    IrNec,
+    IrRc5,         // only with modified firmware, see the rc5-firmware feature
+}
+
+
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    // What the firmware version promises. Probing may take some away.
+    pub fn from_fw_version(fw_version: u8) -> Self {
+        let mut caps = Self::empty();
+        if fw_version == BOOTLOADER_FW_VERSION {
+            return caps;
+        }
+        if fw_version >= 0x01 {
+            caps.insert(Capability::Inputs);
+            caps.insert(Capability::Gpio);
+            caps.insert(Capability::Backlight);
+            caps.insert(Capability::EnterBootloader);
+            caps.insert(Capability::IrNec);
+        }
+        if fw_version >= 0x02 {
+            caps.insert(Capability::Adc);
+            caps.insert(Capability::Temperature);
+            caps.insert(Capability::ChargingState);
+            caps.insert(Capability::Uid);
+            caps.insert(Capability::Scratch);
+            caps.insert(Capability::Ws2812);
+            // The version number does not tell patched firmware apart.
+            #[cfg(feature = "rc5-firmware")]
+            caps.insert(Capability::IrRc5);
+        }
+        caps
+    }
+
+    pub fn contains(self, cap: Capability) -> bool {
+        (self.0 & Self::bit(cap)) != 0
+    }
+
+    pub fn insert(&mut self, cap: Capability) {
+        self.0 |= Self::bit(cap);
+    }
+
+    pub fn remove(&mut self, cap: Capability) {
+        self.0 &= !Self::bit(cap);
+    }
+
+    pub(crate) fn require(self, capability: Capability, fw_version: u8) -> Result<(), Unsupported> {
+        if !self.contains(capability) {
+            return Err(Unsupported { capability, fw_version });
+        }
+        Ok(())
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = Capability> {
+        Capability::iter().filter(move |&cap| self.contains(cap))
+    }
+
+    fn bit(cap: Capability) -> u32 {
+        1 << (cap as u8)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+
+#[derive(Debug, thiserror::Error)]
+#[error("{capability:?} not supported by RP2040 firmware version {fw_version:#X}")]
+pub struct Unsupported {
+    pub capability: Capability,
+    pub fw_version: u8,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::Rp2040Error;
+    use crate::mchcoproc::{Rp2040, Rp2040Sim};
+
+    #[test]
+    fn from_fw_version() {
+        use Capability::*;
+
+        let v1 = [Inputs, Gpio, Backlight, EnterBootloader, IrNec];
+        assert_eq!(Capabilities::from_fw_version(0x01).iter().collect::<Vec<_>>(), v1);
+
+        let v2 = Capabilities::from_fw_version(0x02);
+        let mut expected = vec![
+            Inputs, Gpio, Backlight, EnterBootloader, Adc, Temperature, ChargingState, Uid,
+            Scratch, Ws2812, IrNec,
+        ];
+        if cfg!(feature = "rc5-firmware") {
+            expected.push(IrRc5);
+        }
+        assert_eq!(v2.iter().collect::<Vec<_>>(), expected);
+
+        assert_eq!(Capabilities::from_fw_version(BOOTLOADER_FW_VERSION), Capabilities::empty());
+    }
+
+    #[test]
+    fn probe_drops_missing_uid() {
+        for (uid, supported) in [([0x00; 8], false), ([0xFF; 8], false), ([0x5A; 8], true)] {
+            let sim = Rp2040Sim::new(0x02).shared();
+            sim.lock().unwrap().set_uid(uid);
+            let mut rp2040 = Rp2040::new(sim);
+            rp2040.get_firmware_version().unwrap();
+            let caps = rp2040.probe_capabilities().unwrap();
+            assert_eq!(caps.contains(Capability::Uid), supported);
+            assert_eq!(rp2040.capabilities(), caps);
+            assert_eq!(rp2040.read_uid().is_ok(), supported);
+        }
+    }
+
+    #[test]
+    fn unsupported_without_bus_access() {
+        let sim = Rp2040Sim::new(0x01).shared();
+        let mut rp2040 = Rp2040::new(sim.clone());
+        rp2040.get_firmware_version().unwrap();
+        // Any transfer would fail now, with an I2C error.
+        sim.lock().unwrap().set_offline(true);
+
+        for (result, capability) in [
+            (rp2040.read_uid().map(drop), Capability::Uid),
+            (rp2040.read_temperature().map(drop), Capability::Temperature),
+            (rp2040.read_charging().map(drop), Capability::ChargingState),
+            (rp2040.read_scratch().map(drop), Capability::Scratch),
+        ] {
+            assert!(matches!(
+                result,
+                Err(Rp2040Error::Unsupported(Unsupported { capability: c, fw_version: 0x01 }))
+                    if c == capability),
+                "{:?}", capability);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use embedded_hal::{digital, i2c};
+#[cfg(feature = "esp-idf")]
+use esp_idf_svc::sys::EspError;
+
+use crate::capabilities::Unsupported;
+use crate::ir::IrError;
+use crate::scratch::ScratchError;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum Rp2040Error {
+    #[error("I2C {op} of register {reg:#04X} on {addr:#04X} failed: {kind}")]
+    I2c {
+        op: &'static str, // "read" or "write"
+        addr: u8,
+        reg: u8,
+        kind: i2c::ErrorKind,
+    },
+    #[error(transparent)]
+    Unsupported(#[from] Unsupported),
+    // E.g. bootloader registers while the application is running.
+    #[error("unsupported firmware version {0:#X}")]
+    UnsupportedFirmware(u8),
+    #[error("timeout: {0}")]
+    Timeout(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("task failure: {0}")]
+    Task(String),
+    #[cfg(feature = "esp-idf")]
+    #[error("interrupt pin failed: {0}")]
+    InterruptPin(#[source] EspError),
+    #[cfg(feature = "esp-idf")]
+    #[error("UART failed: {0}")]
+    Uart(#[source] EspError),
+    #[error("bootloader protocol: {0}")]
+    Bootloader(String),
+    #[error(transparent)]
+    BootParams(#[from] ScratchError),
+    #[error(transparent)]
+    Ir(#[from] IrError),
+}
+
+// So Rp2040Pin can be handed to other embedded-hal drivers.
+impl digital::Error for Rp2040Error {
+    fn kind(&self) -> digital::ErrorKind { digital::ErrorKind::Other }
+}
+
+pub(crate) type Result<T = (), E = Rp2040Error> = core::result::Result<T, E>;
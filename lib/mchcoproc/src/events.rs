@@ -1,22 +1,26 @@
-use strum::FromRepr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::Instant;
 
+use strum::{EnumIter, FromRepr, IntoEnumIterator};
 
+
+// Bit numbers in Input1/Input2 and Interrupt1/Interrupt2. Bits 5 and 6
+// are not buttons; see Rp2040Signal.
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, Eq, FromRepr, PartialEq)]
+#[derive(Copy, Clone, Debug, EnumIter, Eq, FromRepr, PartialEq)]
 pub enum Rp2040Input {
     ButtonHome = 0,
-    ButtonMenu,
-    ButtonStart,
-    ButtonAccept,
-    ButtonBack,
-    FpgaCdone,
-    BatteryCharging,
-    ButtonSelect,
-    JoystickLeft,
-    JoystickPress,
-    JoystickDown,
-    JoystickUp,
-    JoystickRight,
+    ButtonMenu = 1,
+    ButtonStart = 2,
+    ButtonAccept = 3,
+    ButtonBack = 4,
+    ButtonSelect = 7,
+    JoystickLeft = 8,
+    JoystickPress = 9,
+    JoystickDown = 10,
+    JoystickUp = 11,
+    JoystickRight = 12,
 }
 
 impl From<Rp2040Input> for u8 {
@@ -24,14 +28,115 @@ impl From<Rp2040Input> for u8 {
 }
 
 
+// Inputs that report system state instead of user actions.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, FromRepr, PartialEq)]
+pub enum Rp2040Signal {
+    FpgaCdone = 5,       // FPGA configuration done
+    BatteryCharging = 6,
+}
+
+impl From<Rp2040Signal> for u8 {
+    fn from(s: Rp2040Signal) -> Self { s as u8 }
+}
+
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Rp2040InputEvent {
     pub input: Rp2040Input,
     pub is_released: bool,
+    pub timestamp: Instant, // when the interrupt task woke up for it
 }
 
 impl Rp2040InputEvent {
-    pub fn new(input: Rp2040Input, is_released: bool) -> Self {
-        Self { input, is_released }
+    pub fn new(input: Rp2040Input, is_released: bool, timestamp: Instant) -> Self {
+        Self { input, is_released, timestamp }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Rp2040SignalEvent {
+    pub signal: Rp2040Signal,
+    pub is_active: bool,
+    pub timestamp: Instant,
+}
+
+impl Rp2040SignalEvent {
+    pub fn new(signal: Rp2040Signal, is_active: bool, timestamp: Instant) -> Self {
+        Self { signal, is_active, timestamp }
+    }
+}
+
+
+// Which inputs are held right now, as of the last read by the input
+// task. Cheap to clone and query from any thread.
+#[derive(Clone, Debug, Default)]
+pub struct InputState(Arc<AtomicU16>);
+
+impl InputState {
+    // Input1/Input2 as one little endian value.
+    pub fn bits(&self) -> u16 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn is_pressed(&self, input: Rp2040Input) -> bool {
+        (self.bits() >> u8::from(input)) & 0x1 != 0
+    }
+
+    pub fn is_active(&self, signal: Rp2040Signal) -> bool {
+        (self.bits() >> u8::from(signal)) & 0x1 != 0
+    }
+
+    pub fn pressed(&self) -> impl Iterator<Item = Rp2040Input> {
+        let bits = self.bits();
+        Rp2040Input::iter().filter(move |&input| (bits >> u8::from(input)) & 0x1 != 0)
+    }
+
+    // Takes a read of Input1, Input2, Interrupt1 and Interrupt2 (which
+    // clears the interrupts): stores the values and decodes the events.
+    pub(crate) fn update(&self, regs: [u8; 4], timestamp: Instant) -> DecodedInputs {
+        let values = u16::from_le_bytes([regs[0], regs[1]]);
+        let interrupts = u16::from_le_bytes([regs[2], regs[3]]);
+        self.0.store(values, Ordering::Relaxed);
+
+        let decoded = decode_inputs(values, interrupts, timestamp);
+        if decoded.unknown != 0 {
+            log::warn!("RP2040 unknown input interrupts: {:#06X}", decoded.unknown);
+        }
+        decoded
+    }
+}
+
+// Level of signal in a read of Input1 and Input2.
+pub(crate) fn signal_level(regs: [u8; 2], signal: Rp2040Signal) -> bool {
+    (u16::from_le_bytes(regs) >> u8::from(signal)) & 0x1 != 0
+}
+
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DecodedInputs {
+    pub inputs: Vec<Rp2040InputEvent>,
+    pub signals: Vec<Rp2040SignalEvent>,
+    pub unknown: u16, // interrupt bits we have no name for
+}
+
+// Turn the Input1/Input2 (values) and Interrupt1/Interrupt2 (what
+// changed) register pairs into events, in bit order.
+pub fn decode_inputs(values: u16, interrupts: u16, timestamp: Instant) -> DecodedInputs {
+    let mut decoded = DecodedInputs::default();
+    for idx in 0..16u8 {
+        if ((interrupts >> idx) & 0x1) == 0 {
+            continue;
+        }
+        let is_set = ((values >> idx) & 0x1) != 0;
+        if let Some(input) = Rp2040Input::from_repr(idx) {
+            decoded.inputs.push(Rp2040InputEvent::new(input, !is_set, timestamp));
+        } else if let Some(signal) = Rp2040Signal::from_repr(idx) {
+            decoded.signals.push(Rp2040SignalEvent::new(signal, is_set, timestamp));
+        } else {
+            decoded.unknown |= 1 << idx;
+        }
     }
+    decoded
 }
@@ -0,0 +1,291 @@
+use std::time::{Duration, Instant};
+
+use crate::events::{Rp2040Input, Rp2040InputEvent};
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Gesture {
+    Click(Rp2040Input),
+    DoubleClick(Rp2040Input),
+    LongPress(Rp2040Input),
+    // Held repeat input; count starts at 1.
+    Repeat(Rp2040Input, u32),
+    // Second input pressed while the first is held.
+    Chord(Rp2040Input, Rp2040Input),
+}
+
+
+#[derive(Clone, Debug)]
+pub struct GestureConfig {
+    // Second press must start this soon after the first release. Clicks
+    // are reported only after it expires; zero disables double clicks.
+    pub double_click_window: Duration,
+    pub long_press: Duration,
+    pub repeat_delay: Duration,
+    pub repeat_interval: Duration,
+    // These click on press and then repeat while held, instead of
+    // double clicking or long pressing.
+    pub repeat_inputs: Vec<Rp2040Input>,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            double_click_window: Duration::from_millis(300),
+            long_press: Duration::from_millis(700),
+            repeat_delay: Duration::from_millis(400),
+            repeat_interval: Duration::from_millis(100),
+            repeat_inputs: vec![
+                Rp2040Input::JoystickLeft,
+                Rp2040Input::JoystickDown,
+                Rp2040Input::JoystickUp,
+                Rp2040Input::JoystickRight,
+            ],
+        }
+    }
+}
+
+
+struct Held {
+    input: Rp2040Input,
+    pressed_at: Instant,
+    // Already reported as long press or chord; the release says nothing.
+    consumed: bool,
+    second_click: bool,
+    repeats: u32,
+}
+
+
+// Turns raw press/release events into gestures. Pure: events carry
+// their own timestamp; the caller feeds every event and calls poll()
+// (at the latest at next_deadline()) so time based gestures fire.
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    held: Vec<Held>,
+    // Released once, waiting for a possible second click.
+    pending_click: Option<(Rp2040Input, Instant)>,
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self { config, held: Vec::new(), pending_click: None }
+    }
+
+    pub fn config(&self) -> &GestureConfig {
+        &self.config
+    }
+
+    pub fn feed(&mut self, event: &Rp2040InputEvent) -> Vec<Gesture> {
+        let mut gestures = self.poll(event.timestamp);
+        if event.is_released {
+            self.release(event.input, event.timestamp, &mut gestures);
+        } else {
+            self.press(event.input, event.timestamp, &mut gestures);
+        }
+        gestures
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+
+        if let Some((input, released_at)) = self.pending_click {
+            if now >= released_at + self.config.double_click_window {
+                self.pending_click = None;
+                gestures.push(Gesture::Click(input));
+            }
+        }
+
+        for held in self.held.iter_mut().filter(|held| !held.consumed) {
+            if self.config.repeat_inputs.contains(&held.input) {
+                if now >= repeat_at(&self.config, held) {
+                    held.repeats += 1;
+                    gestures.push(Gesture::Repeat(held.input, held.repeats));
+                }
+            } else if now >= held.pressed_at + self.config.long_press {
+                held.consumed = true;
+                gestures.push(Gesture::LongPress(held.input));
+            }
+        }
+        gestures
+    }
+
+    // When poll() may have something new; None if nothing is pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let click = self.pending_click
+            .map(|(_, released_at)| released_at + self.config.double_click_window);
+        let held = self.held.iter()
+            .filter(|held| !held.consumed)
+            .map(|held| {
+                if self.config.repeat_inputs.contains(&held.input) {
+                    repeat_at(&self.config, held)
+                } else {
+                    held.pressed_at + self.config.long_press
+                }
+            });
+        click.into_iter().chain(held).min()
+    }
+
+    // Forget everything, e.g. when the app loses focus.
+    pub fn reset(&mut self) {
+        self.held.clear();
+        self.pending_click = None;
+    }
+
+    fn press(&mut self, input: Rp2040Input, now: Instant, gestures: &mut Vec<Gesture>) {
+        if self.held.iter().any(|held| held.input == input) {
+            return; // missed a release; keep the original press
+        }
+
+        let mut second_click = false;
+        if let Some((pending, _)) = self.pending_click.take() {
+            if pending == input {
+                second_click = true;
+            } else {
+                gestures.push(Gesture::Click(pending));
+            }
+        }
+
+        let mut consumed = false;
+        if let Some(first) = self.held.iter_mut().find(|held| !held.consumed) {
+            first.consumed = true;
+            consumed = true;
+            gestures.push(Gesture::Chord(first.input, input));
+        } else if self.config.repeat_inputs.contains(&input) {
+            gestures.push(Gesture::Click(input));
+        }
+
+        self.held.push(Held { input, pressed_at: now, consumed, second_click, repeats: 0 });
+    }
+
+    fn release(&mut self, input: Rp2040Input, now: Instant, gestures: &mut Vec<Gesture>) {
+        let Some(idx) = self.held.iter().position(|held| held.input == input) else {
+            return;
+        };
+        let held = self.held.remove(idx);
+        if held.consumed || self.config.repeat_inputs.contains(&input) {
+            return;
+        }
+
+        if held.second_click {
+            gestures.push(Gesture::DoubleClick(input));
+        } else if self.config.double_click_window.is_zero() {
+            gestures.push(Gesture::Click(input));
+        } else {
+            self.pending_click = Some((input, now));
+        }
+    }
+}
+
+impl Default for GestureRecognizer {
+    fn default() -> Self {
+        Self::new(GestureConfig::default())
+    }
+}
+
+
+fn repeat_at(config: &GestureConfig, held: &Held) -> Instant {
+    held.pressed_at + config.repeat_delay + config.repeat_interval * held.repeats
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Rp2040Input::{ButtonAccept, ButtonBack, ButtonHome, JoystickUp};
+
+    struct Clock(Instant);
+
+    impl Clock {
+        fn at(&self, ms: u64) -> Instant {
+            self.0 + Duration::from_millis(ms)
+        }
+
+        fn press(&self, input: Rp2040Input, ms: u64) -> Rp2040InputEvent {
+            Rp2040InputEvent::new(input, false, self.at(ms))
+        }
+
+        fn release(&self, input: Rp2040Input, ms: u64) -> Rp2040InputEvent {
+            Rp2040InputEvent::new(input, true, self.at(ms))
+        }
+    }
+
+    fn setup() -> (Clock, GestureRecognizer) {
+        (Clock(Instant::now()), GestureRecognizer::default())
+    }
+
+    #[test]
+    fn click_after_double_click_window() {
+        let (clock, mut gestures) = setup();
+        assert!(gestures.feed(&clock.press(ButtonAccept, 0)).is_empty());
+        assert!(gestures.feed(&clock.release(ButtonAccept, 100)).is_empty());
+        assert_eq!(gestures.next_deadline(), Some(clock.at(400)));
+        assert!(gestures.poll(clock.at(399)).is_empty());
+        assert_eq!(gestures.poll(clock.at(400)), [Gesture::Click(ButtonAccept)]);
+        assert_eq!(gestures.next_deadline(), None);
+
+        // Another input does not wait for the window.
+        gestures.feed(&clock.press(ButtonAccept, 1000));
+        gestures.feed(&clock.release(ButtonAccept, 1050));
+        assert_eq!(gestures.feed(&clock.press(ButtonBack, 1100)), [Gesture::Click(ButtonAccept)]);
+    }
+
+    #[test]
+    fn click_without_double_click_window() {
+        let config = GestureConfig { double_click_window: Duration::ZERO, ..Default::default() };
+        let mut gestures = GestureRecognizer::new(config);
+        let clock = Clock(Instant::now());
+        gestures.feed(&clock.press(ButtonAccept, 0));
+        assert_eq!(gestures.feed(&clock.release(ButtonAccept, 100)), [Gesture::Click(ButtonAccept)]);
+    }
+
+    #[test]
+    fn double_click() {
+        let (clock, mut gestures) = setup();
+        gestures.feed(&clock.press(ButtonAccept, 0));
+        gestures.feed(&clock.release(ButtonAccept, 100));
+        assert!(gestures.feed(&clock.press(ButtonAccept, 350)).is_empty());
+        assert_eq!(gestures.feed(&clock.release(ButtonAccept, 450)), [Gesture::DoubleClick(ButtonAccept)]);
+        assert!(gestures.poll(clock.at(2000)).is_empty());
+    }
+
+    #[test]
+    fn long_press() {
+        let (clock, mut gestures) = setup();
+        gestures.feed(&clock.press(ButtonHome, 0));
+        assert_eq!(gestures.next_deadline(), Some(clock.at(700)));
+        assert!(gestures.poll(clock.at(699)).is_empty());
+        assert_eq!(gestures.poll(clock.at(700)), [Gesture::LongPress(ButtonHome)]);
+        assert!(gestures.poll(clock.at(1500)).is_empty());
+        // The release is part of the long press.
+        assert!(gestures.feed(&clock.release(ButtonHome, 1600)).is_empty());
+        assert!(gestures.poll(clock.at(3000)).is_empty());
+    }
+
+    #[test]
+    fn repeat_while_held() {
+        let (clock, mut gestures) = setup();
+        assert_eq!(gestures.feed(&clock.press(JoystickUp, 0)), [Gesture::Click(JoystickUp)]);
+        assert!(gestures.poll(clock.at(399)).is_empty());
+        assert_eq!(gestures.poll(clock.at(400)), [Gesture::Repeat(JoystickUp, 1)]);
+        assert_eq!(gestures.next_deadline(), Some(clock.at(500)));
+        assert_eq!(gestures.poll(clock.at(500)), [Gesture::Repeat(JoystickUp, 2)]);
+        assert_eq!(gestures.poll(clock.at(600)), [Gesture::Repeat(JoystickUp, 3)]);
+        // No long press, and the release says nothing.
+        assert!(gestures.feed(&clock.release(JoystickUp, 650)).is_empty());
+        assert!(gestures.poll(clock.at(2000)).is_empty());
+    }
+
+    #[test]
+    fn chord() {
+        let (clock, mut gestures) = setup();
+        gestures.feed(&clock.press(ButtonHome, 0));
+        assert_eq!(gestures.feed(&clock.press(ButtonBack, 200)), [Gesture::Chord(ButtonHome, ButtonBack)]);
+        // Neither becomes a long press or a click.
+        assert!(gestures.poll(clock.at(1000)).is_empty());
+        assert!(gestures.feed(&clock.release(ButtonBack, 1100)).is_empty());
+        assert!(gestures.feed(&clock.release(ButtonHome, 1200)).is_empty());
+        assert!(gestures.poll(clock.at(3000)).is_empty());
+        assert_eq!(gestures.next_deadline(), None);
+    }
+}
@@ -0,0 +1,79 @@
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin, StatefulOutputPin};
+use embedded_hal::i2c::I2c;
+
+use crate::error::{Result, Rp2040Error};
+use crate::mchcoproc::SharedRp2040;
+
+
+// The RP2040 exposes 8 GPIOs through GpioDir/GpioIn/GpioOut.
+pub const RP2040_GPIO_COUNT: u8 = 8;
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GpioDirection {
+    Input,
+    Output,
+}
+
+
+// A single RP2040 GPIO, usable wherever an embedded-hal pin is expected.
+// Every access takes the rp2040 lock.
+pub struct Rp2040Pin<I2C> {
+    rp2040: SharedRp2040<I2C>,
+    pin: u8,
+}
+
+impl<I2C: I2c> Rp2040Pin<I2C> {
+    pub fn new(rp2040: SharedRp2040<I2C>, pin: u8) -> Result<Self> {
+        if pin >= RP2040_GPIO_COUNT {
+            return Err(Rp2040Error::InvalidArgument(format!("RP2040 GPIO {} out of range", pin)));
+        }
+        Ok(Self { rp2040, pin })
+    }
+
+    pub fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    pub fn set_direction(&mut self, direction: GpioDirection) -> Result {
+        self.rp2040.lock().unwrap().gpio_set_direction(self.pin, direction)
+    }
+}
+
+impl<I2C> ErrorType for Rp2040Pin<I2C> {
+    type Error = Rp2040Error;
+}
+
+impl<I2C: I2c> InputPin for Rp2040Pin<I2C> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.rp2040.lock().unwrap().gpio_read(self.pin)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_high()?)
+    }
+}
+
+impl<I2C: I2c> OutputPin for Rp2040Pin<I2C> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.rp2040.lock().unwrap().gpio_write(self.pin, false)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.rp2040.lock().unwrap().gpio_write(self.pin, true)
+    }
+}
+
+impl<I2C: I2c> StatefulOutputPin for Rp2040Pin<I2C> {
+    fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+        self.rp2040.lock().unwrap().gpio_is_set_high(self.pin)
+    }
+
+    fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.is_set_high()?)
+    }
+
+    fn toggle(&mut self) -> Result<(), Self::Error> {
+        self.rp2040.lock().unwrap().gpio_toggle(self.pin)
+    }
+}
@@ -0,0 +1,281 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::I2c;
+
+use crate::error::{Result, Rp2040Error};
+use crate::events::{Rp2040InputEvent, Rp2040SignalEvent};
+use crate::interrupt::{InterruptLine, Wakeup};
+use crate::mchcoproc::SharedRp2040;
+use crate::subscribers::Subscribers;
+
+
+#[derive(Clone, Debug)]
+pub struct InputTaskConfig {
+    pub stack_size: usize,
+    // FreeRTOS priority and core; None keeps the esp-idf default.
+    // Ignored off-device.
+    pub priority: Option<u8>,
+    pub pin_to_core: Option<usize>,
+    // Read the inputs at least this often, even without an interrupt,
+    // in case an edge got lost.
+    pub watchdog_interval: Duration,
+    // Poll this often while the line is stuck low: no edge will come.
+    pub stuck_poll_interval: Duration,
+    // Retry failed reads after min_backoff, doubling up to max_backoff.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for InputTaskConfig {
+    fn default() -> Self {
+        Self {
+            stack_size: 4096,
+            priority: None,
+            pin_to_core: None,
+            watchdog_interval: Duration::from_secs(1),
+            stuck_poll_interval: Duration::from_millis(20),
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InputHealth {
+    pub running: bool,
+    // The interrupt line is stuck low and we are polling instead.
+    pub polling: bool,
+    // The interrupt line could not be used at all; polling for good.
+    pub line_failed: bool,
+    pub consecutive_errors: u32,
+    pub total_errors: u32,
+    pub last_read: Option<Instant>, // last successful read
+}
+
+impl InputHealth {
+    // Running on a working interrupt line, reading fine, and not silent
+    // for much longer than the watchdog interval.
+    pub fn is_healthy(&self, config: &InputTaskConfig, now: Instant) -> bool {
+        self.running
+            && !self.line_failed
+            && self.consecutive_errors == 0
+            && self.last_read.is_some_and(|at| now.duration_since(at) < 3 * config.watchdog_interval)
+    }
+}
+
+pub(crate) type SharedHealth = Arc<Mutex<InputHealth>>;
+
+
+pub(crate) fn spawn_input_task<I2C, L>(
+    line: L,
+    config: InputTaskConfig,
+    input_events: Subscribers<Rp2040InputEvent>,
+    signal_events: Subscribers<Rp2040SignalEvent>,
+    health: SharedHealth,
+    rp2040: SharedRp2040<I2C>,
+) -> Result
+where
+    I2C: I2c + Send + 'static,
+    L: InterruptLine,
+{
+    #[cfg(feature = "esp-idf")]
+    let previous = set_spawn_configuration(&config)?;
+    let spawned = thread::Builder::new()
+        .name("rp2040-int".into())
+        .stack_size(config.stack_size)
+        .spawn(move || {
+            health.lock().unwrap().running = true;
+            let mut task = InputTask {
+                config, rp2040, input_events, signal_events, health, backoff: None, line_failed: false,
+            };
+
+            // Only returns if the line cannot be used at all. Then we
+            // poll, which is slower but keeps the buttons working.
+            let Err(err) = line.run(|wakeup| task.on_wake(wakeup));
+            log::error!("RP2040 interrupt line failed, polling from now on: {}", err);
+            task.line_failed = true;
+            {
+                let mut health = task.health.lock().unwrap();
+                health.line_failed = true;
+                health.polling = true;
+            }
+            loop {
+                let timeout = task.on_wake(Wakeup { interrupted: false, line_low: false });
+                thread::sleep(timeout.min(task.config.stuck_poll_interval));
+            }
+        });
+    #[cfg(feature = "esp-idf")]
+    if let Some(previous) = previous {
+        restore_spawn_configuration(previous);
+    }
+    spawned.map_err(|err| Rp2040Error::Task(format!("spawn failed: {}", err)))?;
+    Ok(())
+}
+
+
+struct InputTask<I2C> {
+    config: InputTaskConfig,
+    rp2040: SharedRp2040<I2C>,
+    input_events: Subscribers<Rp2040InputEvent>,
+    signal_events: Subscribers<Rp2040SignalEvent>,
+    health: SharedHealth,
+    backoff: Option<Duration>, // set while reads fail
+    line_failed: bool, // polling without a line, see spawn_input_task()
+}
+
+impl<I2C: I2c> InputTask<I2C> {
+    // Read and publish the events; returns how long to wait for the next
+    // interrupt.
+    fn on_wake(&mut self, wakeup: Wakeup) -> Duration {
+        // After device boot, we first need to clear all events:
+        // - Rp2040SignalEvent { signal: FpgaCdone, is_active: true }
+        log::debug!("SPAWN: Read me some events... ({:?})", wakeup);
+        let timestamp = Instant::now();
+
+        // Get all events and drop the rp2040 lock immediately.
+        // (Generally 1 after ISR poke, 1 at boot, or 0 after
+        // firmware restart.)
+        let result = self.rp2040.lock().unwrap().read_inputs(timestamp);
+        let decoded = match result {
+            Ok(decoded) => decoded,
+            Err(err) => return self.on_error(err),
+        };
+
+        for ev in decoded.inputs {
+            log::info!("Got event: {:?}", ev);
+            self.input_events.publish(ev);
+        }
+        for ev in decoded.signals {
+            log::info!("Got signal: {:?}", ev);
+            self.signal_events.publish(ev);
+        }
+
+        if self.backoff.take().is_some() {
+            log::info!("RP2040 inputs readable again");
+        }
+        // Low without an edge: the line never went high after the last
+        // read, so no interrupt will come for new events either.
+        let stuck = wakeup.line_low && !wakeup.interrupted;
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_errors = 0;
+        health.last_read = Some(timestamp);
+        // A failed line stays failed.
+        if !self.line_failed && stuck != health.polling {
+            log::warn!("RP2040 interrupt line {}", if stuck { "stuck low, polling" } else { "back to normal" });
+            health.polling = stuck;
+        }
+        if stuck { self.config.stuck_poll_interval } else { self.config.watchdog_interval }
+    }
+
+    fn on_error(&mut self, err: Rp2040Error) -> Duration {
+        let backoff = self.backoff
+            .map_or(self.config.min_backoff, |backoff| backoff * 2)
+            .min(self.config.max_backoff);
+        self.backoff = Some(backoff);
+
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_errors += 1;
+        health.total_errors += 1;
+        // Don't flood the log while the bus is down.
+        if health.consecutive_errors.is_power_of_two() {
+            log::warn!("Could not read RP2040 inputs ({} times): {}", health.consecutive_errors, err);
+        }
+        backoff
+    }
+}
+
+
+// Returns the configuration to restore after spawning, if it changed.
+#[cfg(feature = "esp-idf")]
+fn set_spawn_configuration(
+    config: &InputTaskConfig,
+) -> Result<Option<esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration>> {
+    use esp_idf_svc::hal::cpu::Core;
+    use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
+
+    if config.priority.is_none() && config.pin_to_core.is_none() {
+        return Ok(None);
+    }
+    let previous = ThreadSpawnConfiguration::get().unwrap_or_default();
+    let defaults = ThreadSpawnConfiguration::default();
+    let pin_to_core = match config.pin_to_core {
+        None => None,
+        Some(0) => Some(Core::Core0),
+        Some(1) => Some(Core::Core1),
+        Some(core) => return Err(Rp2040Error::InvalidArgument(format!("no CPU core {}", core))),
+    };
+    ThreadSpawnConfiguration {
+        name: Some(b"rp2040-int\0"),
+        stack_size: config.stack_size,
+        priority: config.priority.unwrap_or(defaults.priority),
+        pin_to_core,
+        ..defaults
+    }
+    .set()
+    .map_err(|err| Rp2040Error::Task(format!("thread configuration: {}", err)))?;
+    Ok(Some(previous))
+}
+
+// Put back what the application had configured for its own threads.
+#[cfg(feature = "esp-idf")]
+fn restore_spawn_configuration(previous: esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration) {
+    if let Err(err) = previous.set() {
+        log::warn!("Could not restore thread configuration: {}", err);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::Infallible;
+
+    use crate::events::Rp2040Input;
+    use crate::mchcoproc::{InputFilter, Rp2040, Rp2040Sim};
+
+    struct BrokenLine;
+
+    impl InterruptLine for BrokenLine {
+        fn run<F: FnMut(Wakeup) -> Duration>(self, _on_wake: F) -> Result<Infallible> {
+            Err(Rp2040Error::Task("no interrupt line".into()))
+        }
+    }
+
+    #[test]
+    fn failed_line_keeps_polling() {
+        let sim = Rp2040Sim::default().shared();
+        let rp2040 = Rp2040::new(sim.clone());
+        let events = rp2040.subscribe(InputFilter::all());
+        let rp2040 = rp2040.setup_interrupt(BrokenLine).unwrap();
+
+        // Polling still delivers the buttons.
+        sim.lock().unwrap().press(Rp2040Input::ButtonAccept);
+        let event = events.receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!((event.input, event.is_released), (Rp2040Input::ButtonAccept, false));
+
+        // Reads without an interrupt do not make it look normal again.
+        thread::sleep(Duration::from_millis(100));
+        let health = rp2040.lock().unwrap().input_health();
+        assert!(health.running && health.polling && health.line_failed);
+        assert_eq!(health.consecutive_errors, 0);
+        assert!(!health.is_healthy(&InputTaskConfig::default(), Instant::now()));
+    }
+
+    #[test]
+    fn healthy() {
+        let config = InputTaskConfig::default();
+        let now = Instant::now();
+        let health = InputHealth { running: true, last_read: Some(now), ..Default::default() };
+        assert!(health.is_healthy(&config, now));
+        // Stuck low but polling still counts.
+        assert!(InputHealth { polling: true, ..health }.is_healthy(&config, now));
+        assert!(!InputHealth { line_failed: true, polling: true, ..health }.is_healthy(&config, now));
+        assert!(!InputHealth { consecutive_errors: 1, ..health }.is_healthy(&config, now));
+        assert!(!health.is_healthy(&config, now + 3 * config.watchdog_interval));
+        assert!(!InputHealth { running: false, ..health }.is_healthy(&config, now));
+    }
+}
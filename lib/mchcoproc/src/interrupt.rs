@@ -0,0 +1,144 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use crate::error::Result;
+
+
+// Why on_wake was called.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Wakeup {
+    pub interrupted: bool, // false: the timeout ran out
+    pub line_low: bool,    // level of the line right now
+}
+
+
+// The RP2040 pulls its interrupt line low while it has unread input
+// events. This is the platform part of watching that line; the driver
+// spawns a task that calls run().
+pub trait InterruptLine: Send + 'static {
+    // Runs on the input task. Call on_wake once right away (there may be
+    // events from before we started), then again every time the line is
+    // pulled low or the timeout returned by on_wake runs out. If waiting
+    // for the interrupt fails, sleep for the timeout instead. Only
+    // returns if the line cannot be used at all.
+    fn run<F: FnMut(Wakeup) -> Duration>(self, on_wake: F) -> Result<Infallible>;
+}
+
+
+#[cfg(feature = "esp-idf")]
+pub use self::esp::EspInterruptLine;
+
+#[cfg(feature = "esp-idf")]
+mod esp {
+    use std::convert::Infallible;
+    use std::num::NonZero;
+    use std::thread;
+    use std::time::Duration;
+
+    use esp_idf_svc::hal::delay::TICK_RATE_HZ;
+    use esp_idf_svc::hal::gpio::{Input, InputPin, InterruptType, Pin, PinDriver};
+    use esp_idf_svc::hal::task::notification;
+
+    use super::{InterruptLine, Wakeup};
+    use crate::error::{Result, Rp2040Error};
+
+
+    // The RP2040 interrupt on an ESP32 GPIO (GPIO34 on the badge).
+    pub struct EspInterruptLine<PIN: Pin + InputPin> {
+        // IMPORTANT! Don't drop the pindriver, as the driver would reset
+        // the pin.
+        pindriver: PinDriver<'static, PIN, Input>,
+    }
+
+    impl<PIN: Pin + InputPin> EspInterruptLine<PIN> {
+        pub fn new(pin: PIN) -> Result<Self> {
+            // This logs:
+            // I ... gpio: GPIO[34]| InputEn: 0| OutputEn: 0| OpenDrain: 0| Pullup: 0..
+            let mut pindriver = PinDriver::input(pin).map_err(Rp2040Error::InterruptPin)?;
+
+            #[cfg(feature = "unused-force-pullup")] // pindriver.set_pull(Pull::Up)
+            force_gpio_set_pull(&mut pindriver, esp_idf_svc::hal::gpio::Pull::Up);
+
+            pindriver.set_interrupt_type(InterruptType::NegEdge).map_err(Rp2040Error::InterruptPin)?;
+            Ok(Self { pindriver })
+        }
+    }
+
+    impl<PIN: Pin + InputPin> InterruptLine for EspInterruptLine<PIN> {
+        fn run<F: FnMut(Wakeup) -> Duration>(mut self, mut on_wake: F) -> Result<Infallible> {
+            // The notification belongs to the task it is created on.
+            let notification = notification::Notification::new();
+            let notifier = notification.notifier();
+            unsafe {
+                // This must be fast. Supposedly we should use
+                // subscribe_nonstatic() because notifier could otherwise
+                // go out of scope, but it appears to work with
+                // subscribe() too.
+                self.pindriver.subscribe(move || {
+                    notifier.notify_and_yield(NonZero::new(1).unwrap());
+                }).map_err(Rp2040Error::InterruptPin)?;
+            }
+
+            let mut wakeup = Wakeup { interrupted: true, line_low: self.pindriver.is_low() };
+            loop {
+                let timeout = on_wake(wakeup);
+
+                // IMPORTANT! Run after every handle. If it fails, the
+                // caller's timeout is all we have.
+                let interrupted = match self.pindriver.enable_interrupt() {
+                    Ok(()) => notification.wait(to_ticks(timeout)).is_some(),
+                    Err(err) => {
+                        log::warn!("Could not enable RP2040 interrupt: {}", err);
+                        thread::sleep(timeout);
+                        false
+                    }
+                };
+                wakeup = Wakeup { interrupted, line_low: self.pindriver.is_low() };
+            }
+        }
+    }
+
+
+    fn to_ticks(timeout: Duration) -> u32 {
+        let ticks = timeout.as_millis() * (TICK_RATE_HZ as u128) / 1000;
+        ticks.clamp(1, u32::MAX as u128 - 1) as u32
+    }
+
+
+    #[cfg(feature = "unused-force-pullup")]
+    // > GPIOs 34 to 39 are GPIs - input only pins. These pins don't have
+    // > internal pull-ups or pull-down resistors. They can't be used as
+    // > outputs, so use these pins only as inputs.
+    //
+    // Supposedly.
+    //
+    // So, GPIO34 does not implement OutputPin. And then intpin.set_pull(Pull::Up)
+    // is not implemented. Instead we force it anyway, as otherwise things don't
+    // work.
+    //
+    // This seemed to be the case, but after further testing, we can do without.
+    // Leave the code behind a feature toggle for now.
+    fn force_gpio_set_pull<PIN: Pin + InputPin>(
+        pindriver: &mut PinDriver<'static, PIN, Input>,
+        mode: esp_idf_svc::hal::gpio::Pull,
+    ) {
+        match mode {
+            esp_idf_svc::hal::gpio::Pull::Floating => unsafe {
+                esp_idf_svc::hal::sys::gpio_pulldown_dis(pindriver.pin());
+                esp_idf_svc::hal::sys::gpio_pullup_dis(pindriver.pin());
+            },
+            esp_idf_svc::hal::gpio::Pull::Down => unsafe {
+                esp_idf_svc::hal::sys::gpio_pulldown_en(pindriver.pin());
+                esp_idf_svc::hal::sys::gpio_pullup_dis(pindriver.pin());
+            },
+            esp_idf_svc::hal::gpio::Pull::Up => unsafe {
+                esp_idf_svc::hal::sys::gpio_pullup_en(pindriver.pin());
+                esp_idf_svc::hal::sys::gpio_pulldown_dis(pindriver.pin());
+            },
+            esp_idf_svc::hal::gpio::Pull::UpDown => unsafe {
+                esp_idf_svc::hal::sys::gpio_pulldown_en(pindriver.pin());
+                esp_idf_svc::hal::sys::gpio_pullup_en(pindriver.pin());
+            },
+        }
+    }
+}
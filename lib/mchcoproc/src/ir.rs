@@ -0,0 +1,340 @@
+// Infrared commands, their validation, and encoders to mark/space
+// timings.
+//
+// The RP2040 firmware does the modulation itself: the driver only
+// writes address, command and protocol to IrAddressLo..IrTrigger. The
+// stock firmware sends NEC (trigger 0x01); patched firmware also sends
+// RC5 (0x02, or 0x03 with the toggle bit set) and, through the same
+// trigger, RC5X commands above 0x3F. SIRC and raw timings can be encoded
+// but not (yet) sent.
+use crate::capabilities::Capability;
+
+
+const RC5_CARRIER_HZ: u32 = 36_000;
+const RC5_HALF_BIT_US: u32 = 889;
+
+const NEC_CARRIER_HZ: u32 = 38_000;
+const NEC_LEADER_MARK_US: u32 = 9000;
+const NEC_LEADER_SPACE_US: u32 = 4500;
+const NEC_MARK_US: u32 = 562;
+const NEC_ZERO_SPACE_US: u32 = 562;
+const NEC_ONE_SPACE_US: u32 = 1687;
+
+const SIRC_CARRIER_HZ: u32 = 40_000;
+const SIRC_HEADER_MARK_US: u32 = 2400;
+const SIRC_SPACE_US: u32 = 600;
+const SIRC_ZERO_MARK_US: u32 = 600;
+const SIRC_ONE_MARK_US: u32 = 1200;
+
+// IrTrigger values understood by the RP2040 firmware.
+const TRIGGER_NEC: u8 = 0x01;
+const TRIGGER_RC5: u8 = 0x02;
+const TRIGGER_RC5_TOGGLE: u8 = 0x03;
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrProtocol {
+    Rc5,
+    Rc5x,
+    Nec,
+    Sirc12,
+    Sirc15,
+    Sirc20,
+    Raw,
+}
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IrCommand {
+    // Philips RC5: 5-bit address, 6-bit command.
+    Rc5 { address: u8, command: u8 },
+    // RC5 extended: the field bit carries a 7th command bit.
+    Rc5x { address: u8, command: u8 },
+    // NEC with a 16-bit address, sent low byte first. For plain NEC use
+    // IrCommand::nec(), which adds the inverted address byte.
+    Nec { address: u16, command: u8 },
+    // Sony SIRC: 7-bit command and 5, 8 or 5+8 bits of address.
+    Sirc12 { address: u8, command: u8 },
+    Sirc15 { address: u8, command: u8 },
+    Sirc20 { address: u8, extended: u8, command: u8 },
+    // Mark/space durations in microseconds, starting with a mark.
+    Raw { carrier_hz: u32, timings: Vec<u32> },
+}
+
+impl IrCommand {
+    pub fn nec(address: u8, command: u8) -> Self {
+        IrCommand::Nec { address: u16::from_le_bytes([address, !address]), command }
+    }
+
+    pub fn protocol(&self) -> IrProtocol {
+        match self {
+            IrCommand::Rc5 { .. } => IrProtocol::Rc5,
+            IrCommand::Rc5x { .. } => IrProtocol::Rc5x,
+            IrCommand::Nec { .. } => IrProtocol::Nec,
+            IrCommand::Sirc12 { .. } => IrProtocol::Sirc12,
+            IrCommand::Sirc15 { .. } => IrProtocol::Sirc15,
+            IrCommand::Sirc20 { .. } => IrProtocol::Sirc20,
+            IrCommand::Raw { .. } => IrProtocol::Raw,
+        }
+    }
+
+    // Only RC5 and RC5X have a toggle bit.
+    pub fn has_toggle(&self) -> bool {
+        matches!(self, IrCommand::Rc5 { .. } | IrCommand::Rc5x { .. })
+    }
+
+    pub fn validate(&self) -> Result<(), IrError> {
+        let protocol = self.protocol();
+        let (address, max_address, command, max_command) = match *self {
+            IrCommand::Rc5 { address, command } => (address as u16, 0x1F, command, 0x3F),
+            IrCommand::Rc5x { address, command } => (address as u16, 0x1F, command, 0x7F),
+            IrCommand::Nec { .. } => return Ok(()),
+            IrCommand::Sirc12 { address, command } => (address as u16, 0x1F, command, 0x7F),
+            IrCommand::Sirc15 { address, command } => (address as u16, 0xFF, command, 0x7F),
+            IrCommand::Sirc20 { address, command, .. } => (address as u16, 0x1F, command, 0x7F),
+            IrCommand::Raw { carrier_hz, ref timings } => {
+                if carrier_hz == 0 || timings.is_empty() || timings.contains(&0) {
+                    return Err(IrError::InvalidTimings);
+                }
+                return Ok(());
+            }
+        };
+        if address > max_address {
+            return Err(IrError::AddressOutOfRange { protocol, address, max: max_address });
+        }
+        if command > max_command {
+            return Err(IrError::CommandOutOfRange { protocol, command, max: max_command });
+        }
+        Ok(())
+    }
+
+    // What the RP2040 would put on the wire. toggle is ignored by
+    // protocols without a toggle bit.
+    pub fn encode(&self, toggle: bool) -> Result<IrFrame, IrError> {
+        self.validate()?;
+        let frame = match *self {
+            IrCommand::Rc5 { address, command } | IrCommand::Rc5x { address, command } => {
+                IrFrame { carrier_hz: RC5_CARRIER_HZ, timings: encode_rc5(address, command, toggle) }
+            }
+            IrCommand::Nec { address, command } => {
+                IrFrame { carrier_hz: NEC_CARRIER_HZ, timings: encode_nec(address, command) }
+            }
+            IrCommand::Sirc12 { address, command } => {
+                IrFrame { carrier_hz: SIRC_CARRIER_HZ, timings: encode_sirc(command, address as u32, 5) }
+            }
+            IrCommand::Sirc15 { address, command } => {
+                IrFrame { carrier_hz: SIRC_CARRIER_HZ, timings: encode_sirc(command, address as u32, 8) }
+            }
+            IrCommand::Sirc20 { address, extended, command } => {
+                let address = (address as u32) | ((extended as u32) << 5);
+                IrFrame { carrier_hz: SIRC_CARRIER_HZ, timings: encode_sirc(command, address, 13) }
+            }
+            IrCommand::Raw { carrier_hz, ref timings } => IrFrame { carrier_hz, timings: timings.clone() },
+        };
+        Ok(frame)
+    }
+
+    // What the RP2040 firmware needs to send this; None if it cannot.
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            IrCommand::Rc5 { .. } | IrCommand::Rc5x { .. } => Some(Capability::IrRc5),
+            IrCommand::Nec { .. } => Some(Capability::IrNec),
+            _ => None,
+        }
+    }
+
+    // Capability needed and IrAddressLo, IrAddressHi, IrCommand,
+    // IrTrigger values to send this with the RP2040 firmware. Keep in
+    // line with capability().
+    pub(crate) fn trigger_registers(&self, toggle: bool) -> Result<(Capability, [u8; 4]), IrError> {
+        self.validate()?;
+        match *self {
+            IrCommand::Rc5 { address, command } | IrCommand::Rc5x { address, command } => {
+                let trigger = if toggle { TRIGGER_RC5_TOGGLE } else { TRIGGER_RC5 };
+                Ok((Capability::IrRc5, [address, 0, command, trigger]))
+            }
+            IrCommand::Nec { address, command } => {
+                let [lo, hi] = address.to_le_bytes();
+                Ok((Capability::IrNec, [lo, hi, command, TRIGGER_NEC]))
+            }
+            _ => Err(IrError::UnsupportedProtocol(self.protocol())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IrFrame {
+    pub carrier_hz: u32,
+    // Microseconds, alternating mark and space, starting with a mark.
+    // No trailing space.
+    pub timings: Vec<u32>,
+}
+
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum IrError {
+    #[error("{protocol:?} address {address:#X} out of range, max is {max:#X}")]
+    AddressOutOfRange { protocol: IrProtocol, address: u16, max: u16 },
+    #[error("{protocol:?} command {command:#X} out of range, max is {max:#X}")]
+    CommandOutOfRange { protocol: IrProtocol, command: u8, max: u8 },
+    #[error("raw IR needs a carrier and non-zero timings")]
+    InvalidTimings,
+    #[error("RP2040 firmware cannot send {0:?}")]
+    UnsupportedProtocol(IrProtocol),
+}
+
+
+// 14 bits, MSB first: start, field (inverted command bit 6), toggle,
+// 5 address bits, 6 command bits. Manchester coded: a 1 is space then
+// mark, a 0 mark then space.
+pub fn encode_rc5(address: u8, command: u8, toggle: bool) -> Vec<u32> {
+    let field = command & 0x40 == 0;
+    let bits = (1u16 << 13)
+        | ((field as u16) << 12)
+        | ((toggle as u16) << 11)
+        | (((address & 0x1F) as u16) << 6)
+        | ((command & 0x3F) as u16);
+
+    let mut timings: Vec<u32> = Vec::new();
+    let mut mark = false; // idle; that is not a space yet
+    for idx in (0..14).rev() {
+        let one = (bits >> idx) & 0x1 != 0;
+        for half in [!one, one] {
+            if half != mark {
+                timings.push(RC5_HALF_BIT_US);
+                mark = half;
+            } else if let Some(last) = timings.last_mut() {
+                *last += RC5_HALF_BIT_US;
+            }
+        }
+    }
+    if !mark {
+        timings.pop();
+    }
+    timings
+}
+
+// Leader, then address and command with its inverse, LSB first, and a
+// final mark.
+pub fn encode_nec(address: u16, command: u8) -> Vec<u32> {
+    let bits = (address as u32) | ((command as u32) << 16) | ((!command as u32) << 24);
+    let mut timings = vec![NEC_LEADER_MARK_US, NEC_LEADER_SPACE_US];
+    for idx in 0..32 {
+        let space = if (bits >> idx) & 0x1 != 0 { NEC_ONE_SPACE_US } else { NEC_ZERO_SPACE_US };
+        timings.extend([NEC_MARK_US, space]);
+    }
+    timings.push(NEC_MARK_US);
+    timings
+}
+
+// Header, then 7 command bits and address_bits address bits, LSB first.
+// The bit value is in the mark length.
+pub fn encode_sirc(command: u8, address: u32, address_bits: u32) -> Vec<u32> {
+    let bits = ((command & 0x7F) as u32) | (address << 7);
+    let mut timings = vec![SIRC_HEADER_MARK_US];
+    for idx in 0..7 + address_bits {
+        let mark = if (bits >> idx) & 0x1 != 0 { SIRC_ONE_MARK_US } else { SIRC_ZERO_MARK_US };
+        timings.extend([SIRC_SPACE_US, mark]);
+    }
+    timings
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bits as sent, from a NEC (space length) or SIRC (mark length)
+    // frame without its leader.
+    fn bits(durations: impl Iterator<Item = u32>, one: u32) -> Vec<u8> {
+        durations.map(|duration| (duration == one) as u8).collect()
+    }
+
+    #[test]
+    fn rc5_manchester() {
+        let half_bits = |command: IrCommand, toggle| -> Vec<u32> {
+            let frame = command.encode(toggle).unwrap();
+            assert_eq!(frame.carrier_hz, RC5_CARRIER_HZ);
+            frame.timings.iter().map(|us| us / RC5_HALF_BIT_US).collect()
+        };
+        // Start, field, toggle, address 10000, command 001101.
+        let command = IrCommand::Rc5 { address: 0x10, command: 13 };
+        assert_eq!(half_bits(command.clone(), false), [1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 2, 2, 1]);
+        assert_eq!(half_bits(command, true), [1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 2, 2, 1]);
+        // RC5X: command bit 6 clears the field bit.
+        let command = IrCommand::Rc5x { address: 0x10, command: 13 | 0x40 };
+        assert_eq!(half_bits(command, false), [2, 1, 1, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 1, 1, 2, 2, 1]);
+    }
+
+    #[test]
+    fn nec_frame() {
+        let frame = IrCommand::nec(0x04, 0x08).encode(false).unwrap();
+        assert_eq!(frame.carrier_hz, NEC_CARRIER_HZ);
+        assert_eq!(frame.timings.len(), 67);
+        assert_eq!(frame.timings[..2], [NEC_LEADER_MARK_US, NEC_LEADER_SPACE_US]);
+        assert!(frame.timings[2..].iter().step_by(2).all(|&mark| mark == NEC_MARK_US));
+
+        let bits = bits(frame.timings[3..].iter().step_by(2).copied(), NEC_ONE_SPACE_US);
+        let byte = |idx: usize| bits[8 * idx..][..8].iter().rev().fold(0u8, |acc, &bit| (acc << 1) | bit);
+        assert_eq!([byte(0), byte(1), byte(2), byte(3)], [0x04, 0xFB, 0x08, 0xF7]);
+    }
+
+    #[test]
+    fn sirc_bit_order() {
+        let frame = IrCommand::Sirc12 { address: 0x01, command: 21 }.encode(false).unwrap();
+        assert_eq!(frame.carrier_hz, SIRC_CARRIER_HZ);
+        assert_eq!(frame.timings.len(), 1 + 2 * 12);
+        assert_eq!(frame.timings[0], SIRC_HEADER_MARK_US);
+        assert!(frame.timings[1..].iter().step_by(2).all(|&space| space == SIRC_SPACE_US));
+        // Command LSB first, then the address LSB first.
+        let sent = bits(frame.timings[2..].iter().step_by(2).copied(), SIRC_ONE_MARK_US);
+        assert_eq!(sent, [1, 0, 1, 0, 1, 0, 0, 1, 0, 0, 0, 0]);
+
+        let frame = IrCommand::Sirc20 { address: 0x01, extended: 0x80, command: 0 }.encode(false).unwrap();
+        let sent = bits(frame.timings[2..].iter().step_by(2).copied(), SIRC_ONE_MARK_US);
+        assert_eq!(sent.len(), 20);
+        assert_eq!(sent[7..], [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn validate_ranges() {
+        use IrProtocol::*;
+        let address = |protocol, address, max| Err(IrError::AddressOutOfRange { protocol, address, max });
+        let command = |protocol, command, max| Err(IrError::CommandOutOfRange { protocol, command, max });
+
+        assert_eq!(IrCommand::Rc5 { address: 0x1F, command: 0x3F }.validate(), Ok(()));
+        assert_eq!(IrCommand::Rc5 { address: 0x20, command: 0 }.validate(), address(Rc5, 0x20, 0x1F));
+        assert_eq!(IrCommand::Rc5 { address: 0, command: 0x40 }.validate(), command(Rc5, 0x40, 0x3F));
+        assert_eq!(IrCommand::Rc5x { address: 0x20, command: 0 }.validate(), address(Rc5x, 0x20, 0x1F));
+        assert_eq!(IrCommand::Rc5x { address: 0, command: 0x80 }.validate(), command(Rc5x, 0x80, 0x7F));
+        assert_eq!(IrCommand::Nec { address: 0xFFFF, command: 0xFF }.validate(), Ok(()));
+        assert_eq!(IrCommand::Sirc12 { address: 0x20, command: 0 }.validate(), address(Sirc12, 0x20, 0x1F));
+        assert_eq!(IrCommand::Sirc12 { address: 0, command: 0x80 }.validate(), command(Sirc12, 0x80, 0x7F));
+        assert_eq!(IrCommand::Sirc15 { address: 0xFF, command: 0x7F }.validate(), Ok(()));
+        assert_eq!(IrCommand::Sirc15 { address: 0, command: 0x80 }.validate(), command(Sirc15, 0x80, 0x7F));
+        let sirc20 = |address, command| IrCommand::Sirc20 { address, extended: 0xFF, command };
+        assert_eq!(sirc20(0x20, 0).validate(), address(Sirc20, 0x20, 0x1F));
+        assert_eq!(sirc20(0, 0x80).validate(), command(Sirc20, 0x80, 0x7F));
+
+        let raw = |carrier_hz, timings: &[u32]| IrCommand::Raw { carrier_hz, timings: timings.to_vec() };
+        assert_eq!(raw(38_000, &[500, 500, 500]).validate(), Ok(()));
+        assert_eq!(raw(0, &[500]).validate(), Err(IrError::InvalidTimings));
+        assert_eq!(raw(38_000, &[]).validate(), Err(IrError::InvalidTimings));
+        assert_eq!(raw(38_000, &[500, 0, 500]).validate(), Err(IrError::InvalidTimings));
+    }
+
+    #[test]
+    fn trigger_registers() {
+        let rc5 = IrCommand::Rc5 { address: 0x10, command: 13 };
+        assert_eq!(rc5.trigger_registers(false), Ok((Capability::IrRc5, [0x10, 0, 13, TRIGGER_RC5])));
+        assert_eq!(rc5.trigger_registers(true), Ok((Capability::IrRc5, [0x10, 0, 13, TRIGGER_RC5_TOGGLE])));
+        let nec = IrCommand::nec(0x04, 0x08);
+        assert_eq!(nec.trigger_registers(false), Ok((Capability::IrNec, [0x04, 0xFB, 0x08, TRIGGER_NEC])));
+
+        let sirc = IrCommand::Sirc12 { address: 1, command: 21 };
+        assert_eq!(sirc.trigger_registers(false), Err(IrError::UnsupportedProtocol(IrProtocol::Sirc12)));
+        assert_eq!(sirc.capability(), None);
+        let invalid = IrCommand::Rc5 { address: 0x20, command: 0 };
+        assert!(matches!(invalid.trigger_registers(false), Err(IrError::AddressOutOfRange { .. })));
+    }
+}
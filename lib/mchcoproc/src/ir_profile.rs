@@ -0,0 +1,310 @@
+// IR remote profiles: which IR command each badge input sends, read
+// from a small config file (see parse_profiles()).
+use crate::events::Rp2040Input;
+use crate::ir::{IrCommand, IrError, IrProtocol};
+
+
+// Home and menu are taken: launcher and profile picker.
+const RESERVED_INPUTS: [Rp2040Input; 2] = [Rp2040Input::ButtonHome, Rp2040Input::ButtonMenu];
+
+
+// One remote control: which IR command each badge input sends.
+#[derive(Clone, Debug)]
+pub struct IrProfile {
+    pub name: String,
+    pub protocol: IrProtocol,
+    pub buttons: Vec<(Rp2040Input, IrCommand)>,
+}
+
+impl IrProfile {
+    pub fn command_for(&self, input: Rp2040Input) -> Option<&IrCommand> {
+        self.buttons.iter().find(|(i, _)| *i == input).map(|(_, command)| command)
+    }
+}
+
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+#[error("line {line}: {kind}")]
+pub struct ProfileError {
+    pub line: usize,
+    pub kind: ProfileErrorKind,
+}
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ProfileErrorKind {
+    #[error("setting outside of a [profile]")]
+    OutsideProfile,
+    #[error("expected key = value")]
+    NotKeyValue,
+    #[error("{0} must come before the buttons")]
+    AfterButtons(&'static str),
+    #[error("unknown protocol {0:?}")]
+    UnknownProtocol(String),
+    #[error("bad number {0:?}")]
+    BadNumber(String),
+    #[error("unknown input {0:?}")]
+    UnknownInput(String),
+    #[error("input {0:?} is reserved")]
+    ReservedInput(String),
+    #[error("command {0:#X} out of range")]
+    CommandOutOfRange(u16),
+    #[error("{protocol:?} address {address:#X} out of range")]
+    AddressOutOfRange { protocol: IrProtocol, address: u16 },
+    #[error(transparent)]
+    Ir(#[from] IrError),
+}
+
+
+// Profiles in a small INI style format, one section per device:
+//
+//   # comment
+//   [Living room TV]
+//   protocol = rc5          # rc5, rc5x, nec, sirc12, sirc15, sirc20
+//   address = 0x00
+//   button_accept = 13      # input name = command
+//   joystick_up = 16
+//
+// Input names are the Rp2040Input names in snake_case, except
+// button_home and button_menu. Numbers are decimal or 0x hex. NEC
+// addresses above 0xFF are sent as extended NEC.
+pub fn parse_profiles(text: &str) -> Result<Vec<IrProfile>, ProfileError> {
+    let mut profiles: Vec<IrProfile> = Vec::new();
+    let mut address: u16 = 0;
+
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let at_line = |kind: ProfileErrorKind| ProfileError { line: lineno + 1, kind };
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // RC5 at address 0 unless the profile says otherwise.
+            profiles.push(IrProfile { name: name.trim().to_string(), protocol: IrProtocol::Rc5, buttons: Vec::new() });
+            address = 0;
+            continue;
+        }
+        let Some(profile) = profiles.last_mut() else {
+            return Err(at_line(ProfileErrorKind::OutsideProfile));
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(at_line(ProfileErrorKind::NotKeyValue));
+        };
+        let (key, value) = (key.trim(), value.trim());
+
+        match key {
+            "protocol" => {
+                if !profile.buttons.is_empty() {
+                    return Err(at_line(ProfileErrorKind::AfterButtons("protocol")));
+                }
+                profile.protocol = parse_protocol(value).map_err(at_line)?;
+            }
+            "address" => {
+                if !profile.buttons.is_empty() {
+                    return Err(at_line(ProfileErrorKind::AfterButtons("address")));
+                }
+                address = parse_number(value).map_err(at_line)?;
+            }
+            _ => {
+                let input = parse_input(key).map_err(at_line)?;
+                let command = parse_number(value)
+                    .and_then(|command| u8::try_from(command).map_err(|_| ProfileErrorKind::CommandOutOfRange(command)))
+                    .and_then(|command| ir_command(profile.protocol, address, command))
+                    .map_err(at_line)?;
+                profile.buttons.retain(|(i, _)| *i != input);
+                profile.buttons.push((input, command));
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+fn parse_protocol(value: &str) -> Result<IrProtocol, ProfileErrorKind> {
+    Ok(match value.to_ascii_lowercase().as_str() {
+        "rc5" => IrProtocol::Rc5,
+        "rc5x" => IrProtocol::Rc5x,
+        "nec" => IrProtocol::Nec,
+        "sirc12" => IrProtocol::Sirc12,
+        "sirc15" => IrProtocol::Sirc15,
+        "sirc20" => IrProtocol::Sirc20,
+        _ => return Err(ProfileErrorKind::UnknownProtocol(value.to_string())),
+    })
+}
+
+fn parse_number(value: &str) -> Result<u16, ProfileErrorKind> {
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| ProfileErrorKind::BadNumber(value.to_string()))
+}
+
+fn parse_input(key: &str) -> Result<Rp2040Input, ProfileErrorKind> {
+    let input = (0..16)
+        .filter_map(Rp2040Input::from_repr)
+        .find(|&input| input_name(input) == key)
+        .ok_or_else(|| ProfileErrorKind::UnknownInput(key.to_string()))?;
+    if RESERVED_INPUTS.contains(&input) {
+        return Err(ProfileErrorKind::ReservedInput(key.to_string()));
+    }
+    Ok(input)
+}
+
+// JoystickUp -> joystick_up
+fn input_name(input: Rp2040Input) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", input).chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+fn ir_command(protocol: IrProtocol, address: u16, command: u8) -> Result<IrCommand, ProfileErrorKind> {
+    let out_of_range = || ProfileErrorKind::AddressOutOfRange { protocol, address };
+    let narrow = || u8::try_from(address).map_err(|_| out_of_range());
+    let ir_command = match protocol {
+        IrProtocol::Rc5 => IrCommand::Rc5 { address: narrow()?, command },
+        IrProtocol::Rc5x => IrCommand::Rc5x { address: narrow()?, command },
+        IrProtocol::Nec => match u8::try_from(address) {
+            Ok(address) => IrCommand::nec(address, command),
+            Err(_) => IrCommand::Nec { address, command },
+        },
+        IrProtocol::Sirc12 => IrCommand::Sirc12 { address: narrow()?, command },
+        IrProtocol::Sirc15 => IrCommand::Sirc15 { address: narrow()?, command },
+        // 5 bits device, 8 bits extended.
+        IrProtocol::Sirc20 => {
+            if address > 0x1FFF {
+                return Err(out_of_range());
+            }
+            IrCommand::Sirc20 { address: (address & 0x1F) as u8, extended: (address >> 5) as u8, command }
+        }
+        IrProtocol::Raw => return Err(IrError::UnsupportedProtocol(protocol).into()),
+    };
+    ir_command.validate()?;
+    Ok(ir_command)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(text: &str) -> ProfileError {
+        parse_profiles(text).unwrap_err()
+    }
+
+    fn single(text: &str) -> IrProfile {
+        let mut profiles = parse_profiles(text).unwrap();
+        assert_eq!(profiles.len(), 1);
+        profiles.pop().unwrap()
+    }
+
+    #[test]
+    fn example_file() {
+        let profiles = parse_profiles(include_str!("../../../ir_remotes.conf.example")).unwrap();
+        assert_eq!(profiles.len(), 2);
+
+        let rc5 = &profiles[0];
+        assert_eq!((rc5.name.as_str(), rc5.protocol), ("RC5 TV", IrProtocol::Rc5));
+        assert_eq!(rc5.buttons, [
+            (Rp2040Input::ButtonAccept, IrCommand::Rc5 { address: 0x10, command: 13 }),
+            (Rp2040Input::JoystickUp, IrCommand::Rc5 { address: 0x10, command: 16 }),
+            (Rp2040Input::JoystickDown, IrCommand::Rc5 { address: 0x10, command: 17 }),
+        ]);
+
+        let nec = &profiles[1];
+        assert_eq!((nec.name.as_str(), nec.protocol), ("LG TV", IrProtocol::Nec));
+        assert_eq!(nec.buttons, [
+            (Rp2040Input::ButtonAccept, IrCommand::nec(0x04, 0x09)),
+            (Rp2040Input::JoystickUp, IrCommand::nec(0x04, 0x02)),
+            (Rp2040Input::JoystickDown, IrCommand::nec(0x04, 0x03)),
+        ]);
+        assert_eq!(nec.command_for(Rp2040Input::JoystickUp), Some(&IrCommand::nec(0x04, 0x02)));
+        assert_eq!(nec.command_for(Rp2040Input::ButtonBack), None);
+    }
+
+    #[test]
+    fn layout_errors() {
+        assert_eq!(parse_err("# remotes\nprotocol = nec\n[TV]"),
+                   ProfileError { line: 2, kind: ProfileErrorKind::OutsideProfile });
+        assert_eq!(parse_err("[TV]\njoystick_up"),
+                   ProfileError { line: 2, kind: ProfileErrorKind::NotKeyValue });
+        assert_eq!(parse_err("[TV]\nbutton_accept = 1\nprotocol = nec"),
+                   ProfileError { line: 3, kind: ProfileErrorKind::AfterButtons("protocol") });
+        assert_eq!(parse_err("[TV]\nbutton_accept = 1\n\naddress = 2"),
+                   ProfileError { line: 4, kind: ProfileErrorKind::AfterButtons("address") });
+        assert_eq!(parse_err("[TV]\nprotocol = jvc").kind,
+                   ProfileErrorKind::UnknownProtocol("jvc".to_string()));
+        assert_eq!(parse_err("[TV]\naddress = 0x10000").kind,
+                   ProfileErrorKind::BadNumber("0x10000".to_string()));
+
+        // A new section starts over at RC5, address 0.
+        let profiles = parse_profiles("[A]\nprotocol = nec\naddress = 4\n[B]\nbutton_back = 1").unwrap();
+        assert_eq!(profiles[1].buttons, [(Rp2040Input::ButtonBack, IrCommand::Rc5 { address: 0, command: 1 })]);
+    }
+
+    #[test]
+    fn out_of_range_per_protocol() {
+        use IrProtocol::*;
+
+        let address_err = |protocol, address| ProfileErrorKind::AddressOutOfRange { protocol, address };
+        let ir_address_err = |protocol, address, max| {
+            ProfileErrorKind::Ir(IrError::AddressOutOfRange { protocol, address, max })
+        };
+        let ir_command_err = |protocol, command, max| {
+            ProfileErrorKind::Ir(IrError::CommandOutOfRange { protocol, command, max })
+        };
+        for (protocol, address, command, kind) in [
+            ("rc5", 0x20, 0, ir_address_err(Rc5, 0x20, 0x1F)),
+            ("rc5", 0x100, 0, address_err(Rc5, 0x100)),
+            ("rc5", 0, 0x40, ir_command_err(Rc5, 0x40, 0x3F)),
+            ("rc5x", 0, 0x80, ir_command_err(Rc5x, 0x80, 0x7F)),
+            ("nec", 0, 0x100, ProfileErrorKind::CommandOutOfRange(0x100)),
+            ("sirc12", 0x20, 0, ir_address_err(Sirc12, 0x20, 0x1F)),
+            ("sirc12", 0, 0x80, ir_command_err(Sirc12, 0x80, 0x7F)),
+            ("sirc15", 0x100, 0, address_err(Sirc15, 0x100)),
+            ("sirc15", 0, 0x80, ir_command_err(Sirc15, 0x80, 0x7F)),
+            ("sirc20", 0x2000, 0, address_err(Sirc20, 0x2000)),
+            ("sirc20", 0, 0x80, ir_command_err(Sirc20, 0x80, 0x7F)),
+        ] {
+            let text = format!("[TV]\nprotocol = {}\naddress = {:#X}\nbutton_accept = {:#X}",
+                               protocol, address, command);
+            assert_eq!(parse_err(&text), ProfileError { line: 4, kind }, "{}", text);
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        // Above 0xFF: extended NEC, the address as given.
+        let nec = single("[TV]\nprotocol = nec\naddress = 0x1234\nbutton_accept = 5");
+        assert_eq!(nec.buttons, [(Rp2040Input::ButtonAccept, IrCommand::Nec { address: 0x1234, command: 5 })]);
+        let nec = single("[TV]\nprotocol = nec\naddress = 0xFF\nbutton_accept = 5");
+        assert_eq!(nec.buttons, [(Rp2040Input::ButtonAccept, IrCommand::Nec { address: 0x00FF, command: 5 })]);
+
+        // Low 5 bits device, the rest extended.
+        let sirc = single("[TV]\nprotocol = sirc20\naddress = 0x1A5\nbutton_accept = 0x7F");
+        assert_eq!(sirc.buttons, [
+            (Rp2040Input::ButtonAccept, IrCommand::Sirc20 { address: 0x05, extended: 0x0D, command: 0x7F }),
+        ]);
+    }
+
+    #[test]
+    fn buttons() {
+        // The last one wins, in the order of its last line.
+        let profile = single("[TV]\njoystick_up = 1\nbutton_back = 2\njoystick_up = 3");
+        assert_eq!(profile.buttons, [
+            (Rp2040Input::ButtonBack, IrCommand::Rc5 { address: 0, command: 2 }),
+            (Rp2040Input::JoystickUp, IrCommand::Rc5 { address: 0, command: 3 }),
+        ]);
+
+        assert_eq!(parse_err("[TV]\nbutton_home = 1"),
+                   ProfileError { line: 2, kind: ProfileErrorKind::ReservedInput("button_home".to_string()) });
+        assert_eq!(parse_err("[TV]\nbutton_menu = 1").kind,
+                   ProfileErrorKind::ReservedInput("button_menu".to_string()));
+        assert_eq!(parse_err("[TV]\nbutton_x = 1").kind, ProfileErrorKind::UnknownInput("button_x".to_string()));
+        assert_eq!(parse_err("[TV]\nButtonBack = 1").kind, ProfileErrorKind::UnknownInput("ButtonBack".to_string()));
+    }
+}
@@ -1,4 +1,26 @@
 pub mod mchcoproc;
 
+mod animation;
+mod animator;
+#[cfg(feature = "async")]
+mod asynch;
+mod battery_gauge;
+mod bootloader;
+mod capabilities;
+mod error;
 mod events;
+mod gesture;
+mod gpio;
+mod input_task;
+mod interrupt;
+mod ir;
+mod ir_profile;
+mod power;
+mod power_policy;
 mod registers;
+mod scratch;
+#[cfg(any(test, feature = "sim"))]
+mod sim;
+mod subscribers;
+mod uid;
+mod ws2812;
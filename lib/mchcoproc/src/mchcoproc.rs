@@ -1,269 +1,643 @@
-use std::num::NonZero;
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use embedded_hal::i2c::{Error as _, I2c};
+#[cfg(feature = "esp-idf")]
 use esp_idf_svc::hal::i2c::I2cDriver;
-use esp_idf_svc::hal::delay::TICK_RATE_HZ;
-use esp_idf_svc::hal::gpio::{Input, InputPin, InterruptType, Pin, PinDriver};
-use esp_idf_svc::hal::task::notification;
 
-pub use crate::events::{Rp2040Input, Rp2040InputEvent};
-use crate::registers::Rp2040Reg;
-
-const DEPRECATED_TIMEOUT: u32 = TICK_RATE_HZ; // exactly 1 second
-const RP2040_I2C_ADDR: u8 = 0x17;
+pub use crate::animation::Animation;
+pub use crate::animator::LedAnimator;
+#[cfg(feature = "async")]
+pub use crate::asynch::{run_inputs, Rp2040Async};
+pub use crate::battery_gauge::{lipo_percent, BatteryGauge};
+pub use crate::bootloader::{
+    update_firmware,
+    BootloaderInfo,
+    BootloaderPort,
+    FirmwareUpdater,
+    UpdateStage,
+};
+pub use crate::capabilities::{Capabilities, Capability, Unsupported};
+pub use crate::error::Rp2040Error;
+use crate::error::Result;
+pub use crate::events::{
+    decode_inputs,
+    DecodedInputs,
+    InputState,
+    Rp2040Input,
+    Rp2040InputEvent,
+    Rp2040Signal,
+    Rp2040SignalEvent,
+};
+use crate::events::signal_level;
+pub use crate::gesture::{Gesture, GestureConfig, GestureRecognizer};
+pub use crate::gpio::{GpioDirection, Rp2040Pin, RP2040_GPIO_COUNT};
+#[cfg(feature = "esp-idf")]
+pub use crate::interrupt::EspInterruptLine;
+pub use crate::input_task::{InputHealth, InputTaskConfig};
+use crate::input_task::{spawn_input_task, SharedHealth};
+pub use crate::interrupt::{InterruptLine, Wakeup};
+pub use crate::ir::{encode_nec, encode_rc5, encode_sirc, IrCommand, IrError, IrFrame, IrProtocol};
+pub use crate::ir_profile::{parse_profiles, IrProfile, ProfileError, ProfileErrorKind};
+pub use crate::power::{ChargingState, PowerStatus};
+pub use crate::power_policy::{PowerPolicy, ScreenState};
+use crate::power::adc_to_volts;
+use crate::registers::{Rp2040BlReg, Rp2040Reg};
+pub use crate::scratch::{BootParams, ScratchError, APP_STATE_SIZE, BOOT_TARGET_MAX_LEN, SCRATCH_SIZE};
+pub use crate::subscribers::{EventBit, InputFilter, Subscribers, Subscription, SubscriptionId};
+#[cfg(any(test, feature = "sim"))]
+pub use crate::sim::{Rp2040Sim, SimInterruptLine, SimIrTransmission};
+#[cfg(all(any(test, feature = "sim"), feature = "async"))]
+pub use crate::sim::SimAsyncI2c;
+pub use crate::uid::BoardUid;
+pub use crate::ws2812::{Rgb, Ws2812Mode, WS2812_MAX_LEDS};
+
+pub(crate) const RP2040_I2C_ADDR: u8 = 0x17;
+// Time the RP2040 needs to sample all ADC channels after AdcTrigger.
+pub(crate) const ADC_SAMPLE_TIME: Duration = Duration::from_millis(5);
 //FIXME: nice function that returns peripherals.gpio34?
 //const GPIO_INT_RP2040: u8 = 34;
-//enum { RP2040_BL_REG_FW_VER, RP2040_BL_REG_BL_VER,
-//       RP2040_BL_REG_BL_STATE, RP2040_BL_REG_BL_CTRL };
-
+// Written to BlTrigger to reboot into the bootloader.
+pub(crate) const BL_TRIGGER_MAGIC: u8 = 0xBE;
+// FwVer reads this while the bootloader runs.
+pub const BOOTLOADER_FW_VERSION: u8 = 0xFF;
 
-#[derive(Debug, thiserror::Error)]
-#[error("unsupported firmware version: {0:#X}")]
-pub struct UnsupportedFirmware(u8);
 
-pub type SharedI2c<'a> = Arc<Mutex<I2cDriver<'a>>>;
+pub type SharedI2c<I2C> = Arc<Mutex<I2C>>;
 
-pub struct Rp2040 {
-    i2c: SharedI2c<'static>,
+// Generic over the I2C bus, so the driver logic also runs off-device.
+pub struct Rp2040<I2C> {
+    i2c: SharedI2c<I2C>,
     addr: u8,
     fw_version: u8,
-    //gpio_dir_bits: u8, // direction (in/out)
-    //gpio_val_bits: u8, // value (off/on)
+    capabilities: Capabilities,
+    // Shadows of GpioDir and GpioOut, so we can change a single bit
+    // without reading back first.
+    gpio_dir_bits: u8, // direction (in/out)
+    gpio_val_bits: u8, // value (off/on)
+    uid: Option<BoardUid>, // never changes, so read once
+    ir_toggle: bool, // RC5 toggle bit of the last key press
+    input_state: InputState,
+    input_events: Subscribers<Rp2040InputEvent>,
+    signal_events: Subscribers<Rp2040SignalEvent>,
+    input_health: SharedHealth,
 }
 
-pub type SharedRp2040 = Arc<Mutex<Rp2040>>;
+#[cfg(feature = "esp-idf")]
+pub type SharedRp2040<I2C = I2cDriver<'static>> = Arc<Mutex<Rp2040<I2C>>>;
+#[cfg(not(feature = "esp-idf"))]
+pub type SharedRp2040<I2C> = Arc<Mutex<Rp2040<I2C>>>;
 
 
-impl Rp2040 {
-    pub fn new(i2c: SharedI2c<'static>) -> Self {
+impl<I2C: I2c> Rp2040<I2C> {
+    pub fn new(i2c: SharedI2c<I2C>) -> Self {
         Self {
             i2c,
             addr: RP2040_I2C_ADDR,
             fw_version: 0,
-            //gpio_dir_bits: 0,
-            //gpio_val_bits: 0,
+            capabilities: Capabilities::empty(),
+            gpio_dir_bits: 0,
+            gpio_val_bits: 0,
+            uid: None,
+            ir_toggle: false,
+            input_state: InputState::default(),
+            input_events: Subscribers::default(),
+            signal_events: Subscribers::default(),
+            input_health: SharedHealth::default(),
         }
     }
 
-    pub fn setup_interrupt<PIN: Pin + InputPin>(
+    // Subscribe before setup_interrupt() to also get the events that
+    // are pending at startup.
+    pub fn subscribe(&self, filter: InputFilter) -> Subscription<Rp2040InputEvent> {
+        self.input_events.subscribe(filter)
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        self.input_events.unsubscribe(id)
+    }
+
+    // Handle to subscribe from elsewhere without holding the rp2040 lock.
+    pub fn input_events(&self) -> Subscribers<Rp2040InputEvent> {
+        self.input_events.clone()
+    }
+
+    // Changes of the system signals (FPGA done, charging).
+    pub fn signal_events(&self) -> Subscribers<Rp2040SignalEvent> {
+        self.signal_events.clone()
+    }
+
+    pub fn setup_interrupt<L: InterruptLine>(
+        self,
+        line: L,
+    ) -> Result<SharedRp2040<I2C>>
+    where
+        I2C: Send + 'static,
+    {
+        self.setup_interrupt_with(line, InputTaskConfig::default())
+    }
+
+    pub fn setup_interrupt_with<L: InterruptLine>(
         mut self,
-        pin: PIN,
-        event_sender: mpsc::Sender<Rp2040InputEvent>,
-    ) -> anyhow::Result<SharedRp2040> {
-        self.fw_version = self.get_firmware_version()?;
-        if self.fw_version < 1 {
-            anyhow::bail!("Unsupported FW version {}", self.fw_version);
+        line: L,
+        config: InputTaskConfig,
+    ) -> Result<SharedRp2040<I2C>>
+    where
+        I2C: Send + 'static,
+    {
+        self.get_firmware_version()?;
+        self.require(Capability::Inputs)?;
+        if let Err(err) = self.probe_capabilities() {
+            log::warn!("Could not probe RP2040 capabilities: {}", err);
         }
 
-        //let mut dir = [0u8];
-        //self.read_reg(Rp2040Reg::GpioDir, &mut dir)?;
-        //self.gpio_dir_bits = dir[0];
-
-        //let mut val = [0u8];
-        //self.read_reg(Rp2040Reg::GpioOut, &mut val)?;
-        //self.gpio_val_bits = val[0];
+        self.read_gpio_shadow()?;
 
+        let (input_events, signal_events) = (self.input_events(), self.signal_events());
         let rp2040 = Arc::new(Mutex::new(self));
-        setup_interrupt_and_task(pin, event_sender, rp2040.clone())?;
+        let health = rp2040.lock().unwrap().input_health.clone();
+        spawn_input_task(line, config, input_events, signal_events, health, rp2040.clone())?;
         Ok(rp2040)
     }
 
-    pub fn get_firmware_version(&mut self) -> anyhow::Result<u8> {
+    pub fn get_firmware_version(&mut self) -> Result<u8> {
         let mut buf = [0u8; 1];
         self.read_reg(Rp2040Reg::FwVer, &mut buf)?;
-        self.fw_version = buf[0];
+        if buf[0] != self.fw_version {
+            self.fw_version = buf[0];
+            self.capabilities = Capabilities::from_fw_version(self.fw_version);
+        }
         Ok(self.fw_version)
     }
 
-    fn read_vbat_raw(&mut self) -> anyhow::Result<u16> {
-        if (self.fw_version < 0x02) || (self.fw_version == 0xFF) {
-            return Err(UnsupportedFirmware(self.fw_version).into());
+    // Kept up to date by the input task started by setup_interrupt().
+    pub fn input_state(&self) -> InputState {
+        self.input_state.clone()
+    }
+
+    // How the input task is doing: read errors, polling instead of
+    // interrupts, last successful read.
+    pub fn input_health(&self) -> InputHealth {
+        *self.input_health.lock().unwrap()
+    }
+
+    // Valid after get_firmware_version() (done by setup_interrupt()).
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // Check what the version number can't tell: whether the UID
+    // registers are implemented (they read all 0x00 or all 0xFF if not).
+    pub fn probe_capabilities(&mut self) -> Result<Capabilities> {
+        if self.capabilities.contains(Capability::Uid) {
+            let mut buf = [0u8; 8];
+            self.read_reg(Rp2040Reg::Uid0, &mut buf)?;
+            if buf.iter().all(|&b| b == 0x00) || buf.iter().all(|&b| b == 0xFF) {
+                self.capabilities.remove(Capability::Uid);
+            }
+        }
+        Ok(self.capabilities)
+    }
+
+    fn require(&self, capability: Capability) -> Result {
+        Ok(self.capabilities.require(capability, self.fw_version)?)
+    }
+
+    // Uses the cached version; call get_firmware_version() to refresh.
+    pub fn is_in_bootloader(&self) -> bool {
+        self.fw_version == BOOTLOADER_FW_VERSION
+    }
+
+    // The RP2040 reboots into its bootloader. Buttons stop working until
+    // it is back in the application.
+    pub fn enter_bootloader(&self) -> Result {
+        self.require(Capability::EnterBootloader)?;
+        self.write_reg(Rp2040Reg::BlTrigger, &[BL_TRIGGER_MAGIC])
+    }
+
+    fn check_bootloader(&self) -> Result {
+        if !self.is_in_bootloader() {
+            return Err(Rp2040Error::UnsupportedFirmware(self.fw_version));
+        }
+        Ok(())
+    }
+
+    pub fn get_bootloader_version(&self) -> Result<u8> {
+        self.check_bootloader()?;
+        let mut buf = [0u8; 1];
+        self.read_reg(Rp2040BlReg::BlVer, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn get_bootloader_state(&self) -> Result<u8> {
+        self.check_bootloader()?;
+        let mut buf = [0u8; 1];
+        self.read_reg(Rp2040BlReg::BlState, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn set_bootloader_ctrl(&self, action: u8) -> Result {
+        self.check_bootloader()?;
+        self.write_reg(Rp2040BlReg::BlCtrl, &[action])
+    }
+
+    pub fn get_lcd_backlight(&self) -> Result<u8> {
+        self.require(Capability::Backlight)?;
+        let mut buf = [0u8; 1];
+        self.read_reg(Rp2040Reg::LcdBacklight, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    // 0 is off, 255 is full brightness.
+    pub fn set_lcd_backlight(&self, brightness: u8) -> Result {
+        self.require(Capability::Backlight)?;
+        self.write_reg(Rp2040Reg::LcdBacklight, &[brightness])
+    }
+
+    // Refresh the GPIO shadow registers from the RP2040. Done by
+    // setup_interrupt(); call it yourself if you don't use that.
+    pub fn read_gpio_shadow(&mut self) -> Result {
+        self.require(Capability::Gpio)?;
+        let mut dir = [0u8];
+        self.read_reg(Rp2040Reg::GpioDir, &mut dir)?;
+        self.gpio_dir_bits = dir[0];
+
+        let mut val = [0u8];
+        self.read_reg(Rp2040Reg::GpioOut, &mut val)?;
+        self.gpio_val_bits = val[0];
+        Ok(())
+    }
+
+    pub fn gpio_set_direction(&mut self, pin: u8, direction: GpioDirection) -> Result {
+        self.require(Capability::Gpio)?;
+        let mask = gpio_mask(pin)?;
+        let bits = match direction {
+            GpioDirection::Input => self.gpio_dir_bits & !mask,
+            GpioDirection::Output => self.gpio_dir_bits | mask,
+        };
+        self.write_reg(Rp2040Reg::GpioDir, &[bits])?;
+        self.gpio_dir_bits = bits;
+        Ok(())
+    }
+
+    pub fn gpio_direction(&self, pin: u8) -> Result<GpioDirection> {
+        self.require(Capability::Gpio)?;
+        let mask = gpio_mask(pin)?;
+        if (self.gpio_dir_bits & mask) != 0 {
+            Ok(GpioDirection::Output)
+        } else {
+            Ok(GpioDirection::Input)
         }
+    }
+
+    pub fn gpio_read(&self, pin: u8) -> Result<bool> {
+        self.require(Capability::Gpio)?;
+        let mask = gpio_mask(pin)?;
+        let mut buf = [0u8];
+        self.read_reg(Rp2040Reg::GpioIn, &mut buf)?;
+        Ok((buf[0] & mask) != 0)
+    }
+
+    pub fn gpio_write(&mut self, pin: u8, high: bool) -> Result {
+        self.require(Capability::Gpio)?;
+        let mask = gpio_mask(pin)?;
+        let bits = if high { self.gpio_val_bits | mask } else { self.gpio_val_bits & !mask };
+        self.write_reg(Rp2040Reg::GpioOut, &[bits])?;
+        self.gpio_val_bits = bits;
+        Ok(())
+    }
+
+    pub fn gpio_toggle(&mut self, pin: u8) -> Result {
+        let high = !self.gpio_is_set_high(pin)?;
+        self.gpio_write(pin, high)
+    }
+
+    // The value we last wrote, not what is on the pin.
+    pub fn gpio_is_set_high(&self, pin: u8) -> Result<bool> {
+        self.require(Capability::Gpio)?;
+        let mask = gpio_mask(pin)?;
+        Ok((self.gpio_val_bits & mask) != 0)
+    }
+
+    // Have the RP2040 take fresh ADC samples (VUSB, VBAT, temperature).
+    // Without this, the ADC registers hold whatever was sampled last.
+    pub fn trigger_adc(&self) -> Result {
+        self.require(Capability::Adc)?;
+        self.write_reg(Rp2040Reg::AdcTrigger, &[1])?;
+        thread::sleep(ADC_SAMPLE_TIME);
+        Ok(())
+    }
+
+    fn read_adc_raw(&self, lo_reg: Rp2040Reg) -> Result<u16> {
         let mut buf = [0u8; 2];
-        self.read_reg(Rp2040Reg::AdcValueVbatLo, &mut buf)?;
+        self.read_reg(lo_reg, &mut buf)?;
         Ok(((buf[1] as u16) << 8) | (buf[0] as u16))
     }
 
-    pub fn read_vbat(&mut self) -> anyhow::Result<f32> {
+    fn read_vbat_raw(&mut self) -> Result<u16> {
+        self.require(Capability::Adc)?;
+        self.read_adc_raw(Rp2040Reg::AdcValueVbatLo)
+    }
+
+    pub fn read_vbat(&mut self) -> Result<f32> {
+        let raw = self.read_vbat_raw()?;
+        Ok(adc_to_volts(raw))
+    }
+
+    fn read_vusb_raw(&mut self) -> Result<u16> {
+        self.require(Capability::Adc)?;
+        self.read_adc_raw(Rp2040Reg::AdcValueVusbLo)
+    }
+
+    pub fn read_vusb(&mut self) -> Result<f32> {
+        let raw = self.read_vusb_raw()?;
+        Ok(adc_to_volts(raw))
+    }
+
+    fn read_temperature_raw(&mut self) -> Result<u16> {
+        self.require(Capability::Temperature)?;
+        self.read_adc_raw(Rp2040Reg::AdcValueTempLo)
+    }
+
+    // Die temperature of the RP2040, in degrees Celsius. Rough; the
+    // sensor is not calibrated.
+    pub fn read_temperature(&mut self) -> Result<f32> {
         // 12-bit ADC with 3.3v vref
         const CONVERSION_FACTOR: f32 = 3.3_f32 / ((1 << 12) as f32);
-        let raw = self.read_vbat_raw()?;
-        // Connected through 100k/100k divider
-        Ok((raw as f32) * CONVERSION_FACTOR * 2.0_f32)
+        let raw = self.read_temperature_raw()?;
+        // From the RP2040 datasheet: Vbe is 0.706V at 27 degrees, with
+        // a slope of -1.721mV per degree.
+        let voltage = (raw as f32) * CONVERSION_FACTOR;
+        Ok(27.0_f32 - (voltage - 0.706_f32) / 0.001721_f32)
+    }
+
+    pub fn read_uid(&mut self) -> Result<BoardUid> {
+        if let Some(uid) = self.uid {
+            return Ok(uid);
+        }
+        self.require(Capability::Uid)?;
+        let mut buf = [0u8; 8];
+        self.read_reg(Rp2040Reg::Uid0, &mut buf)?;
+        let uid = BoardUid::from_bytes(buf);
+        self.uid = Some(uid);
+        Ok(uid)
+    }
+
+    pub fn read_scratch(&self) -> Result<[u8; SCRATCH_SIZE]> {
+        self.require(Capability::Scratch)?;
+        let mut buf = [0u8; SCRATCH_SIZE];
+        self.read_reg(Rp2040Reg::Scratch0, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write_scratch(&self, data: &[u8; SCRATCH_SIZE]) -> Result {
+        self.require(Capability::Scratch)?;
+        self.write_reg(Rp2040Reg::Scratch0, data)
+    }
+
+    // Fails with a ScratchError if nothing (valid) was stored, e.g. after
+    // a power cycle.
+    pub fn read_boot_params(&self) -> Result<BootParams> {
+        let buf = self.read_scratch()?;
+        Ok(BootParams::from_bytes(&buf)?)
+    }
+
+    pub fn write_boot_params(&self, params: &BootParams) -> Result {
+        self.write_scratch(&params.to_bytes()?)
+    }
+
+    // Read, modify and write back the boot parameters, starting from the
+    // defaults if nothing valid was stored.
+    pub fn update_boot_params<F>(&self, f: F) -> Result<BootParams>
+    where
+        F: FnOnce(&mut BootParams),
+    {
+        let buf = self.read_scratch()?;
+        let mut params = BootParams::from_bytes(&buf).unwrap_or_else(|err| {
+            log::info!("Starting with default boot parameters: {}", err);
+            BootParams::default()
+        });
+        f(&mut params);
+        self.write_boot_params(&params)?;
+        Ok(params)
+    }
+
+    pub fn clear_boot_params(&self) -> Result {
+        self.write_scratch(&[0u8; SCRATCH_SIZE])
+    }
+
+    // Whether the charger is charging. Says nothing about USB presence.
+    pub fn read_charging(&mut self) -> Result<bool> {
+        self.require(Capability::ChargingState)?;
+        let mut buf = [0u8; 1];
+        self.read_reg(Rp2040Reg::ChargingState, &mut buf)?;
+        Ok(buf[0] != 0)
+    }
+
+    pub fn read_power_status(&mut self) -> Result<PowerStatus> {
+        self.require(Capability::ChargingState)?;
+        self.trigger_adc()?;
+        let vbat = self.read_adc_raw(Rp2040Reg::AdcValueVbatLo)?;
+        let vusb = self.read_adc_raw(Rp2040Reg::AdcValueVusbLo)?;
+        let mut charging = [0u8; 1];
+        self.read_reg(Rp2040Reg::ChargingState, &mut charging)?;
+        Ok(PowerStatus::from_registers(vbat, vusb, charging[0]))
     }
 
     // These should be read by the local task, which is triggered by the
     // interrupt. Others won't need to be reading this.
-    fn read_inputs(&mut self) -> anyhow::Result<Vec<Rp2040InputEvent>> {
+    pub(crate) fn read_inputs(&mut self, timestamp: Instant) -> Result<DecodedInputs> {
+        self.require(Capability::Inputs)?;
+        // Input1, Input2, Interrupt1, Interrupt2 in one go: reading the
+        // interrupt registers clears them.
         let mut buf = [0u8; 4];
         self.read_reg(Rp2040Reg::Input1, &mut buf)?;
-        let state = u32::from_le_bytes(buf);
-        let interrupt = (state >> 16) as u16;
-        let values = (state & 0xffff) as u16;
-
-        let mut events = Vec::new();
-
-        for idx in 0..16 {
-            if ((interrupt >> idx) & 0x1) != 0 {
-                if let Some(input) = Rp2040Input::from_repr(idx) {
-                    let is_released = ((values >> idx) & 0x1) == 0;
-                    events.push(Rp2040InputEvent::new(input, is_released));
-                }
-            }
-        }
+        Ok(self.input_state.update(buf, timestamp))
+    }
 
-        Ok(events)
+    // Current level of a system signal, without touching the interrupt
+    // flags.
+    pub fn read_signal(&self, signal: Rp2040Signal) -> Result<bool> {
+        self.require(Capability::Inputs)?;
+        let mut buf = [0u8; 2];
+        self.read_reg(Rp2040Reg::Input1, &mut buf)?;
+        Ok(signal_level(buf, signal))
     }
 
-    fn read_reg(&self, reg: Rp2040Reg, buf: &mut [u8]) -> anyhow::Result<()> {
+    fn read_reg(&self, reg: impl Into<u8>, buf: &mut [u8]) -> Result {
+        let reg = reg.into();
         self.i2c.lock().unwrap()
-            .write_read(self.addr, &[reg.into()], buf, DEPRECATED_TIMEOUT)?;
+            .write_read(self.addr, &[reg], buf)
+            .map_err(|err| Rp2040Error::I2c { op: "read", addr: self.addr, reg, kind: err.kind() })
+    }
+
+    // A new key press. RC5 flips the toggle bit on each press, so the
+    // receiver can tell two presses from one held key.
+    // NOTE: RC5 and RC5X require modified RP2040 firmware.
+    pub fn send_ir(&mut self, command: &IrCommand) -> Result {
+        let toggle = !self.ir_toggle;
+        self.write_ir(command, toggle)?;
+        self.ir_toggle = toggle;
         Ok(())
     }
 
-    // NOTE: Writing RC5 infrared requires modified RP2040 firmware.
-    pub fn write_ir_trigger_rc5(
-        &self, toggle: bool,
-        address: u16,
-        command: u16,
-    ) {
-        // IrTrigger 0x2 = RC5, and 0x3 is RC5 with toggle.
-        let ir_proto: u8 = if toggle { 0x2 } else { 0x3 };
-        let buf: [u8; 4] = [
-            (address & 0xff) as u8, // IrAddressLo
-            (address >> 8) as u8,   // IrAddressHi
-            (command & 0xff) as u8, // IrCommand
-            ir_proto,
-        ];
-        self.write_reg(Rp2040Reg::IrAddressLo, &buf).unwrap(); // XXX: unwrap
-    }
-
-    fn write_reg(&self, reg: Rp2040Reg, data: &[u8]) -> anyhow::Result<()> {
+    // The same key still held: keeps the toggle bit of the last send_ir().
+    pub fn send_ir_repeat(&mut self, command: &IrCommand) -> Result {
+        self.write_ir(command, self.ir_toggle)
+    }
+
+    fn write_ir(&self, command: &IrCommand, toggle: bool) -> Result {
+        let (capability, buf) = command.trigger_registers(toggle)?;
+        self.require(capability)?;
+        // IrAddressLo, IrAddressHi, IrCommand, IrTrigger
+        self.write_reg(Rp2040Reg::IrAddressLo, &buf)
+    }
+
+    pub fn set_ws2812_mode(&self, mode: Ws2812Mode) -> Result {
+        self.require(Capability::Ws2812)?;
+        self.write_reg(Rp2040Reg::Ws2812Mode, &[mode.into()])
+    }
+
+    // Number of LEDs the RP2040 shifts out on trigger.
+    pub fn set_ws2812_length(&self, length: usize) -> Result {
+        self.require(Capability::Ws2812)?;
+        if length > WS2812_MAX_LEDS {
+            return Err(Rp2040Error::InvalidArgument(
+                format!("WS2812 length {} exceeds {} LEDs", length, WS2812_MAX_LEDS)));
+        }
+        self.write_reg(Rp2040Reg::Ws2812Length, &[length as u8])
+    }
+
+    pub fn set_ws2812_speed(&self, speed: u8) -> Result {
+        self.require(Capability::Ws2812)?;
+        self.write_reg(Rp2040Reg::Ws2812Speed, &[speed])
+    }
+
+    // Only updates the register; call trigger_ws2812() to show it.
+    pub fn set_ws2812_led(&self, index: usize, color: Rgb) -> Result {
+        self.require(Capability::Ws2812)?;
+        let Some(reg) = Rp2040Reg::ws2812_led_data(index) else {
+            return Err(Rp2040Error::InvalidArgument(
+                format!("WS2812 LED index {} out of range", index)));
+        };
+        self.write_reg(reg, &color.to_register_bytes())
+    }
+
+    // Writes all colors in one I2C transfer and sets the length to match.
+    // Only updates the registers; call trigger_ws2812() to show them.
+    pub fn set_ws2812_leds(&self, colors: &[Rgb]) -> Result {
+        self.require(Capability::Ws2812)?;
+        if colors.len() > WS2812_MAX_LEDS {
+            return Err(Rp2040Error::InvalidArgument(
+                format!("WS2812 got {} colors for {} LEDs", colors.len(), WS2812_MAX_LEDS)));
+        }
+        let data: Vec<u8> = colors.iter().flat_map(|c| c.to_register_bytes()).collect();
+        self.write_reg(Rp2040Reg::Ws2812Led0Data0, &data)?;
+        self.set_ws2812_length(colors.len())
+    }
+
+    pub fn trigger_ws2812(&self) -> Result {
+        self.require(Capability::Ws2812)?;
+        self.write_reg(Rp2040Reg::Ws2812Trigger, &[1])
+    }
+
+    fn write_reg(&self, reg: impl Into<u8>, data: &[u8]) -> Result {
         let mut out = Vec::with_capacity(1 + data.len());
         out.push(reg.into());
         out.extend_from_slice(data);
-        log::info!("write_reg: {:?}", out);
-        self.i2c.lock().unwrap().write(self.addr, &out, DEPRECATED_TIMEOUT)?;
-        Ok(())
+        log::debug!("write_reg: {:?}", out);
+        self.i2c.lock().unwrap()
+            .write(self.addr, &out)
+            .map_err(|err| Rp2040Error::I2c { op: "write", addr: self.addr, reg: out[0], kind: err.kind() })
     }
 }
 
 
-fn setup_interrupt_and_task<PIN: Pin + InputPin>(
-    interrupt_pin: PIN,
-    event_sender: mpsc::Sender<Rp2040InputEvent>,
-    rp2040: Arc<Mutex<Rp2040>>,
-) -> anyhow::Result<()> {
-    // This logs:
-    // I ... gpio: GPIO[34]| InputEn: 0| OutputEn: 0| OpenDrain: 0| Pullup: 0..
-    let mut intpin = PinDriver::input(interrupt_pin)?;
-
-    #[cfg(feature = "unused-force-pullup")] // intpin.set_pull(Pull::Up)
-    force_gpio_set_pull(&mut intpin, esp_idf_svc::hal::gpio::Pull::Up);
-
-    intpin.set_interrupt_type(InterruptType::NegEdge)?;
-
-    // Send task handle back from the spawned thread/task.
-    let (notifier_tx, notifier_rx) = mpsc::channel();
-    // Send the pindriver into the task.
-    let (intpin_tx, intpin_rx) =
-        mpsc::channel::<PinDriver<'static, PIN, Input>>();
-
-    // Task: block until ISR notifies
-    log::info!("PRESPAWN: ISR should be up...");
-    thread::spawn(move || {
-        let notification = notification::Notification::new();
-        notifier_tx.send(notification.notifier()).unwrap();
-
-        // IMPORTANT! Don't drop the intpin, as the driver would reset it.
-        // Luckily we need it because we need to enable_interrupt() on it
-        // after every action.
-        let mut intpin = intpin_rx.recv().unwrap();
-
-        loop {
-            // After device boot, we first need to clear all events:
-            // - Rp2040InputEvent { input: FpgaCdone, is_released: false }
-            log::info!("SPAWN: Read me some events...");
-
-            // Get all events and drop the rp2040 lock immediately.
-            // (Generally 1 after ISR poke, 1 at boot, or 0 after
-            // firmware restart.)
-            let events = {
-                let mut rp = rp2040.lock().unwrap();
-                rp.read_inputs().ok().map(|v| v.to_vec()).unwrap_or_default()
-            };
-
-            for ev in events {
-                log::info!("Got event: {:?}", ev);
-                if let Err(err) = event_sender.send(ev) {
-                    log::warn!("Could not send event: {:?} - {}", ev, err);
-                }
-            }
+fn gpio_mask(pin: u8) -> Result<u8> {
+    if pin >= RP2040_GPIO_COUNT {
+        return Err(Rp2040Error::InvalidArgument(format!("RP2040 GPIO {} out of range", pin)));
+    }
+    Ok(1 << pin)
+}
 
-            // IMPORTANT! Run after every handle.
-            // (If this fails, we cannot do buttons anymore. Might as
-            // well die.)
-            intpin.enable_interrupt().unwrap();
 
-            log::info!("SPAWN: Waiting for ISR...");
-            notification.wait_any();
-        }
-    });
 
-    // The thread/task knows it's "handle". We need that to notify it.
-    let notifier = notifier_rx.recv().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embedded_hal::i2c::{ErrorKind, ErrorType, NoAcknowledgeSource, Operation};
 
-    unsafe {
-        // This must be fast. Supposedly we should use
-        // intpin.subscribe_nonstatic() because notifier could
-        // otherwise go out of scope, but it appears to work
-        // with intpin.subscribe() too.
-        intpin.subscribe(move || {
-            notifier.notify_and_yield(NonZero::new(1).unwrap());
-        })?;
+    // Fails every transfer with the same error.
+    struct FailingI2c(ErrorKind);
+
+    impl ErrorType for FailingI2c {
+        type Error = ErrorKind;
     }
 
-    // We're done with intpin, but our task is not. Move it there.
-    intpin_tx.send(intpin).unwrap();
+    impl I2c for FailingI2c {
+        fn transaction(&mut self, _: u8, _: &mut [Operation<'_>]) -> core::result::Result<(), ErrorKind> {
+            Err(self.0)
+        }
+    }
 
-    Ok(())
-}
+    #[test]
+    fn decode_splits_inputs_and_signals() {
+        let now = Instant::now();
+        let home = 1 << u8::from(Rp2040Input::ButtonHome);
+        let back = 1 << u8::from(Rp2040Input::ButtonBack);
+        let cdone = 1 << u8::from(Rp2040Signal::FpgaCdone);
+        let charging = 1 << u8::from(Rp2040Signal::BatteryCharging);
+
+        // Home pressed, back released, FPGA done, charging stopped, and
+        // an interrupt on a bit without a name.
+        let decoded = decode_inputs(home | cdone, home | back | cdone | charging | 0x8000, now);
+        assert_eq!(decoded.inputs, [
+            Rp2040InputEvent::new(Rp2040Input::ButtonHome, false, now),
+            Rp2040InputEvent::new(Rp2040Input::ButtonBack, true, now),
+        ]);
+        assert_eq!(decoded.signals, [
+            Rp2040SignalEvent::new(Rp2040Signal::FpgaCdone, true, now),
+            Rp2040SignalEvent::new(Rp2040Signal::BatteryCharging, false, now),
+        ]);
+        assert_eq!(decoded.unknown, 0x8000);
+
+        // Values without an interrupt are not events.
+        assert_eq!(decode_inputs(home | cdone, 0, now), DecodedInputs::default());
+    }
+
+    #[test]
+    fn adc_conversion() {
+        assert_eq!(adc_to_volts(0), 0.0);
+        assert_eq!(adc_to_volts(2048), 3.3);
+        assert!((adc_to_volts(4095) - 6.6).abs() < 0.002);
+    }
+
+    #[test]
+    fn i2c_errors_name_the_register() {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let mut rp2040 = Rp2040::new(Arc::new(Mutex::new(FailingI2c(nack))));
+        match rp2040.get_firmware_version() {
+            Err(Rp2040Error::I2c { op: "read", addr: RP2040_I2C_ADDR, reg, kind }) => {
+                assert_eq!((reg, kind), (Rp2040Reg::FwVer.into(), nack));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
 
+        // As if get_firmware_version() had read 0x02.
+        rp2040.fw_version = 0x02;
+        rp2040.capabilities = Capabilities::from_fw_version(0x02);
+        match rp2040.set_lcd_backlight(10) {
+            Err(Rp2040Error::I2c { op: "write", addr: RP2040_I2C_ADDR, reg, kind }) => {
+                assert_eq!((reg, kind), (Rp2040Reg::LcdBacklight.into(), nack));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
 
-#[cfg(feature = "unused-force-pullup")]
-// > GPIOs 34 to 39 are GPIs - input only pins. These pins don't have
-// > internal pull-ups or pull-down resistors. They can't be used as
-// > outputs, so use these pins only as inputs.
-//
-// Supposedly.
-//
-// So, GPIO34 does not implement OutputPin. And then intpin.set_pull(Pull::Up)
-// is not implemented. Instead we force it anyway, as otherwise things don't
-// work.
-//
-// This seemed to be the case, but after further testing, we can do without.
-// Leave the code behind a feature toggle for now.
-fn force_gpio_set_pull<PIN: Pin + InputPin>(
-    pindriver: &mut PinDriver<'static, PIN, Input>,
-    mode: esp_idf_svc::hal::gpio::Pull,
-) {
-    match mode {
-        esp_idf_svc::hal::gpio::Pull::Floating => unsafe {
-            esp_idf_svc::hal::sys::gpio_pulldown_dis(pindriver.pin());
-            esp_idf_svc::hal::sys::gpio_pullup_dis(pindriver.pin());
-        },
-        esp_idf_svc::hal::gpio::Pull::Down => unsafe {
-            esp_idf_svc::hal::sys::gpio_pulldown_en(pindriver.pin());
-            esp_idf_svc::hal::sys::gpio_pullup_dis(pindriver.pin());
-        },
-        esp_idf_svc::hal::gpio::Pull::Up => unsafe {
-            esp_idf_svc::hal::sys::gpio_pullup_en(pindriver.pin());
-            esp_idf_svc::hal::sys::gpio_pulldown_dis(pindriver.pin());
-        },
-        esp_idf_svc::hal::gpio::Pull::UpDown => unsafe {
-            esp_idf_svc::hal::sys::gpio_pulldown_en(pindriver.pin());
-            esp_idf_svc::hal::sys::gpio_pullup_en(pindriver.pin());
-        },
+        let mut rp2040 = Rp2040::new(Arc::new(Mutex::new(FailingI2c(ErrorKind::Bus))));
+        assert!(matches!(
+            rp2040.get_firmware_version(),
+            Err(Rp2040Error::I2c { op: "read", kind: ErrorKind::Bus, .. })));
     }
 }
@@ -0,0 +1,55 @@
+// Anything above this on VUSB means a cable is plugged in.
+const USB_PRESENT_VOLTAGE: f32 = 4.0;
+
+
+// Convert a raw ADC reading of VBAT or VUSB to volts.
+pub fn adc_to_volts(raw: u16) -> f32 {
+    // 12-bit ADC with 3.3v vref
+    const CONVERSION_FACTOR: f32 = 3.3_f32 / ((1 << 12) as f32);
+    // Connected through 100k/100k divider
+    (raw as f32) * CONVERSION_FACTOR * 2.0_f32
+}
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChargingState {
+    Discharging, // on battery
+    Charging,
+    Charged,     // on USB, charger done
+}
+
+impl ChargingState {
+    pub fn from_flags(usb_present: bool, charging: bool) -> Self {
+        match (usb_present, charging) {
+            (false, _) => ChargingState::Discharging,
+            (true, true) => ChargingState::Charging,
+            (true, false) => ChargingState::Charged,
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PowerStatus {
+    pub battery_voltage: f32,
+    pub usb_voltage: f32,
+    pub usb_present: bool,
+    pub charging_state: ChargingState,
+}
+
+impl PowerStatus {
+    pub fn new(battery_voltage: f32, usb_voltage: f32, charging: bool) -> Self {
+        let usb_present = usb_voltage > USB_PRESENT_VOLTAGE;
+        Self {
+            battery_voltage,
+            usb_voltage,
+            usb_present,
+            charging_state: ChargingState::from_flags(usb_present, charging),
+        }
+    }
+
+    // From raw AdcValueVbat, AdcValueVusb and ChargingState.
+    pub(crate) fn from_registers(vbat: u16, vusb: u16, charging: u8) -> Self {
+        Self::new(adc_to_volts(vbat), adc_to_volts(vusb), charging != 0)
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use crate::events::{Rp2040Input, Rp2040InputEvent};
+
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScreenState {
+    On,
+    Dimmed,
+    Off,
+}
+
+
+// Decides when to dim and switch off the screen. This only keeps state
+// and returns the screen state to switch to; the app does the switching.
+pub struct PowerPolicy {
+    dim_after: Duration,
+    off_after: Duration,
+    last_input: Instant,
+    state: ScreenState,
+    // The input that woke us up. Its release is swallowed too.
+    swallowed: Option<Rp2040Input>,
+}
+
+impl PowerPolicy {
+    pub fn new(dim_after: Duration, off_after: Duration, now: Instant) -> Self {
+        Self {
+            dim_after,
+            off_after: off_after.max(dim_after),
+            last_input: now,
+            state: ScreenState::On,
+            swallowed: None,
+        }
+    }
+
+    pub fn state(&self) -> ScreenState {
+        self.state
+    }
+
+    // Feed every input event through here. Returns whether the app
+    // should see the event and the new screen state if it changed. The
+    // press that wakes up a dimmed or dark screen (and its release) is
+    // not for the app.
+    pub fn on_input(
+        &mut self,
+        event: &Rp2040InputEvent,
+        now: Instant,
+    ) -> (bool, Option<ScreenState>) {
+        self.last_input = now;
+
+        if self.swallowed == Some(event.input) {
+            if event.is_released {
+                self.swallowed = None;
+            }
+            return (false, None);
+        }
+
+        if self.state == ScreenState::On {
+            return (true, None);
+        }
+
+        self.state = ScreenState::On;
+        if !event.is_released {
+            self.swallowed = Some(event.input);
+        }
+        (false, Some(ScreenState::On))
+    }
+
+    // Call this periodically. Returns the new screen state if it changed.
+    pub fn poll(&mut self, now: Instant) -> Option<ScreenState> {
+        let idle = now.saturating_duration_since(self.last_input);
+        let wanted = if idle >= self.off_after {
+            ScreenState::Off
+        } else if idle >= self.dim_after {
+            ScreenState::Dimmed
+        } else {
+            ScreenState::On
+        };
+
+        // Only input brings the screen back on.
+        if wanted == self.state || wanted == ScreenState::On {
+            return None;
+        }
+        self.state = wanted;
+        Some(wanted)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIM_AFTER: Duration = Duration::from_secs(30);
+    const OFF_AFTER: Duration = Duration::from_secs(60);
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn event(input: Rp2040Input, is_released: bool, at: Instant) -> Rp2040InputEvent {
+        Rp2040InputEvent::new(input, is_released, at)
+    }
+
+    #[test]
+    fn dim_then_off() {
+        let start = Instant::now();
+        let mut policy = PowerPolicy::new(DIM_AFTER, OFF_AFTER, start);
+        assert_eq!(policy.poll(secs(start, 29)), None);
+        assert_eq!(policy.poll(secs(start, 30)), Some(ScreenState::Dimmed));
+        assert_eq!(policy.poll(secs(start, 45)), None);
+        assert_eq!(policy.poll(secs(start, 60)), Some(ScreenState::Off));
+        assert_eq!(policy.poll(secs(start, 600)), None);
+        assert_eq!(policy.state(), ScreenState::Off);
+    }
+
+    #[test]
+    fn input_resets_the_timer() {
+        let start = Instant::now();
+        let mut policy = PowerPolicy::new(DIM_AFTER, OFF_AFTER, start);
+        let at = secs(start, 20);
+        assert_eq!(policy.on_input(&event(Rp2040Input::ButtonAccept, false, at), at), (true, None));
+        assert_eq!(policy.poll(secs(start, 40)), None);
+        assert_eq!(policy.poll(secs(start, 50)), Some(ScreenState::Dimmed));
+    }
+
+    #[test]
+    fn wake_press_is_swallowed() {
+        let start = Instant::now();
+        let mut policy = PowerPolicy::new(DIM_AFTER, OFF_AFTER, start);
+        policy.poll(secs(start, 60));
+
+        let at = secs(start, 70);
+        let press = event(Rp2040Input::ButtonAccept, false, at);
+        assert_eq!(policy.on_input(&press, at), (false, Some(ScreenState::On)));
+        // Other inputs while it is held do reach the app.
+        let other = event(Rp2040Input::JoystickUp, false, at);
+        assert_eq!(policy.on_input(&other, at), (true, None));
+        let release = event(Rp2040Input::ButtonAccept, true, at);
+        assert_eq!(policy.on_input(&release, at), (false, None));
+        // The next press is for the app again.
+        assert_eq!(policy.on_input(&press, at), (true, None));
+        assert_eq!(policy.state(), ScreenState::On);
+    }
+
+    #[test]
+    fn wake_by_release_swallows_nothing_more() {
+        let start = Instant::now();
+        let mut policy = PowerPolicy::new(DIM_AFTER, OFF_AFTER, start);
+        policy.poll(secs(start, 30));
+
+        // Held since before the dim; its release wakes the screen.
+        let at = secs(start, 35);
+        let release = event(Rp2040Input::ButtonBack, true, at);
+        assert_eq!(policy.on_input(&release, at), (false, Some(ScreenState::On)));
+        let press = event(Rp2040Input::ButtonBack, false, at);
+        assert_eq!(policy.on_input(&press, at), (true, None));
+    }
+}
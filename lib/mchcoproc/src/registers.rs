@@ -1,5 +1,8 @@
+use strum::FromRepr;
+
+
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, FromRepr)]
 pub enum Rp2040Reg {
     FwVer = 0,
     GpioDir,
@@ -168,3 +171,30 @@ pub enum Rp2040Reg {
 impl From<Rp2040Reg> for u8 {
     fn from(r: Rp2040Reg) -> Self { r as u8 }
 }
+
+// Register map while the RP2040 runs its bootloader. FwVer then reads
+// 0xFF.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug)]
+pub enum Rp2040BlReg {
+    FwVer = 0,
+    BlVer,
+    BlState,
+    BlCtrl,
+}
+
+impl From<Rp2040BlReg> for u8 {
+    fn from(r: Rp2040BlReg) -> Self { r as u8 }
+}
+
+impl Rp2040Reg {
+    // First of the 4 data registers of WS2812 LED `index`.
+    pub fn ws2812_led_data(index: usize) -> Option<Rp2040Reg> {
+        let offset = u8::try_from(index.checked_mul(4)?).ok()?;
+        let reg = Rp2040Reg::from_repr(u8::from(Rp2040Reg::Ws2812Led0Data0).checked_add(offset)?)?;
+        if (reg as u8) > (Rp2040Reg::Ws2812Led9Data0 as u8) {
+            return None;
+        }
+        Some(reg)
+    }
+}
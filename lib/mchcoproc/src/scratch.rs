@@ -0,0 +1,184 @@
+// Scratch0..Scratch63 survive an ESP32 reset (but not a power cycle), so
+// that is where we keep boot parameters. The stock launcher does not
+// read them: the badge bootloader's own RTC register decides what boots,
+// these are for launchers and apps that want more.
+//
+// Layout (version 1):
+//   0       magic (SCRATCH_MAGIC)
+//   1       layout version
+//   2       flags (bit 0: return to launcher)
+//   3       reserved, 0
+//   4..52   boot target app name, NUL padded (as long as an AppFS name)
+//   52..62  free for the running app
+//   62..64  Fletcher-16 over bytes 0..62, little endian
+pub const SCRATCH_SIZE: usize = 64;
+pub const BOOT_TARGET_MAX_LEN: usize = 48;
+pub const APP_STATE_SIZE: usize = 10;
+
+const SCRATCH_MAGIC: u8 = 0xB7;
+const SCRATCH_VERSION: u8 = 1;
+
+const FLAG_RETURN_TO_LAUNCHER: u8 = 0x01;
+
+const OFFSET_MAGIC: usize = 0;
+const OFFSET_VERSION: usize = 1;
+const OFFSET_FLAGS: usize = 2;
+const OFFSET_BOOT_TARGET: usize = 4;
+const OFFSET_APP_STATE: usize = OFFSET_BOOT_TARGET + BOOT_TARGET_MAX_LEN;
+const OFFSET_CHECKSUM: usize = OFFSET_APP_STATE + APP_STATE_SIZE;
+
+
+#[derive(Debug, Eq, PartialEq, thiserror::Error)]
+pub enum ScratchError {
+    #[error("no boot parameters (magic {0:#04X})")]
+    BadMagic(u8),
+    #[error("unsupported boot parameter layout version {0}")]
+    UnsupportedVersion(u8),
+    #[error("boot parameter checksum mismatch")]
+    BadChecksum,
+    #[error("boot target name is {0} bytes, max is {BOOT_TARGET_MAX_LEN}")]
+    BootTargetTooLong(usize),
+    #[error("boot target name is not valid UTF-8 or contains a NUL")]
+    BadBootTarget,
+}
+
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BootParams {
+    pub boot_target: Option<String>,
+    pub return_to_launcher: bool,
+    pub app_state: [u8; APP_STATE_SIZE],
+}
+
+impl BootParams {
+    pub fn from_bytes(buf: &[u8; SCRATCH_SIZE]) -> Result<Self, ScratchError> {
+        if buf[OFFSET_MAGIC] != SCRATCH_MAGIC {
+            return Err(ScratchError::BadMagic(buf[OFFSET_MAGIC]));
+        }
+        if buf[OFFSET_VERSION] != SCRATCH_VERSION {
+            return Err(ScratchError::UnsupportedVersion(buf[OFFSET_VERSION]));
+        }
+        let stored = u16::from_le_bytes([buf[OFFSET_CHECKSUM], buf[OFFSET_CHECKSUM + 1]]);
+        if stored != fletcher16(&buf[..OFFSET_CHECKSUM]) {
+            return Err(ScratchError::BadChecksum);
+        }
+
+        let target = &buf[OFFSET_BOOT_TARGET..OFFSET_APP_STATE];
+        let len = target.iter().position(|&b| b == 0).unwrap_or(target.len());
+        let boot_target = match len {
+            0 => None,
+            _ => Some(
+                std::str::from_utf8(&target[..len])
+                    .map_err(|_| ScratchError::BadBootTarget)?
+                    .to_string()),
+        };
+
+        let mut app_state = [0u8; APP_STATE_SIZE];
+        app_state.copy_from_slice(&buf[OFFSET_APP_STATE..OFFSET_CHECKSUM]);
+
+        Ok(Self {
+            boot_target,
+            return_to_launcher: (buf[OFFSET_FLAGS] & FLAG_RETURN_TO_LAUNCHER) != 0,
+            app_state,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<[u8; SCRATCH_SIZE], ScratchError> {
+        let mut buf = [0u8; SCRATCH_SIZE];
+        buf[OFFSET_MAGIC] = SCRATCH_MAGIC;
+        buf[OFFSET_VERSION] = SCRATCH_VERSION;
+        if self.return_to_launcher {
+            buf[OFFSET_FLAGS] |= FLAG_RETURN_TO_LAUNCHER;
+        }
+
+        if let Some(target) = &self.boot_target {
+            let bytes = target.as_bytes();
+            if bytes.len() > BOOT_TARGET_MAX_LEN {
+                return Err(ScratchError::BootTargetTooLong(bytes.len()));
+            }
+            // NUL pads the field; one inside would cut the name short.
+            if bytes.contains(&0) {
+                return Err(ScratchError::BadBootTarget);
+            }
+            buf[OFFSET_BOOT_TARGET..OFFSET_BOOT_TARGET + bytes.len()].copy_from_slice(bytes);
+        }
+
+        buf[OFFSET_APP_STATE..OFFSET_CHECKSUM].copy_from_slice(&self.app_state);
+
+        let checksum = fletcher16(&buf[..OFFSET_CHECKSUM]);
+        buf[OFFSET_CHECKSUM..].copy_from_slice(&checksum.to_le_bytes());
+        Ok(buf)
+    }
+}
+
+
+fn fletcher16(data: &[u8]) -> u16 {
+    let mut sum1: u16 = 0;
+    let mut sum2: u16 = 0;
+    for &byte in data {
+        sum1 = (sum1 + byte as u16) % 255;
+        sum2 = (sum2 + sum1) % 255;
+    }
+    (sum2 << 8) | sum1
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(params: &BootParams) -> BootParams {
+        BootParams::from_bytes(&params.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let params = BootParams::default();
+        assert_eq!(round_trip(&params), params);
+
+        let params = BootParams {
+            boot_target: Some("x".repeat(BOOT_TARGET_MAX_LEN)),
+            return_to_launcher: true,
+            app_state: [0xA5; APP_STATE_SIZE],
+        };
+        assert_eq!(round_trip(&params), params);
+
+        let mut app_state = [0u8; APP_STATE_SIZE];
+        app_state[0] = 1;
+        app_state[APP_STATE_SIZE - 1] = 0xFF;
+        let params = BootParams { boot_target: Some("dünne app".into()), app_state, ..Default::default() };
+        assert_eq!(round_trip(&params), params);
+    }
+
+    #[test]
+    fn rejects_bad_boot_targets() {
+        let params = BootParams { boot_target: Some("x".repeat(BOOT_TARGET_MAX_LEN + 1)), ..Default::default() };
+        assert_eq!(params.to_bytes(), Err(ScratchError::BootTargetTooLong(BOOT_TARGET_MAX_LEN + 1)));
+        let params = BootParams { boot_target: Some("app\0other".into()), ..Default::default() };
+        assert_eq!(params.to_bytes(), Err(ScratchError::BadBootTarget));
+    }
+
+    #[test]
+    fn rejects_bad_bytes() {
+        let good = BootParams { boot_target: Some("app".into()), ..Default::default() }.to_bytes().unwrap();
+
+        assert_eq!(BootParams::from_bytes(&[0u8; SCRATCH_SIZE]), Err(ScratchError::BadMagic(0)));
+        let mut buf = good;
+        buf[OFFSET_VERSION] = 2;
+        assert_eq!(BootParams::from_bytes(&buf), Err(ScratchError::UnsupportedVersion(2)));
+
+        for idx in [OFFSET_FLAGS, OFFSET_BOOT_TARGET, OFFSET_APP_STATE + 3, OFFSET_CHECKSUM + 1] {
+            let mut buf = good;
+            buf[idx] ^= 0x01;
+            assert_eq!(BootParams::from_bytes(&buf), Err(ScratchError::BadChecksum), "byte {}", idx);
+        }
+
+        // Valid checksum over a name that is not UTF-8.
+        let mut buf = good;
+        buf[OFFSET_BOOT_TARGET] = 0xC3;
+        buf[OFFSET_BOOT_TARGET + 1] = 0x28;
+        let checksum = fletcher16(&buf[..OFFSET_CHECKSUM]);
+        buf[OFFSET_CHECKSUM..].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(BootParams::from_bytes(&buf), Err(ScratchError::BadBootTarget));
+    }
+}
@@ -0,0 +1,595 @@
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+use crate::error::Result;
+use crate::events::{Rp2040Input, Rp2040Signal};
+use crate::interrupt::{InterruptLine, Wakeup};
+use crate::mchcoproc::{BL_TRIGGER_MAGIC, BOOTLOADER_FW_VERSION, RP2040_I2C_ADDR};
+use crate::registers::Rp2040Reg;
+use crate::ws2812::{Rgb, WS2812_MAX_LEDS};
+
+
+const SIM_FW_VERSION: u8 = 0x02;
+// How often SimInterruptLine looks at the interrupt line.
+const SIM_INTERRUPT_POLL: Duration = Duration::from_millis(1);
+
+
+// One IrTrigger write, with the address and command registers as they
+// were at that moment.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SimIrTransmission {
+    pub address: u16,
+    pub command: u8,
+    pub trigger: u8,
+}
+
+
+// Software model of the RP2040 coprocessor, as seen over I2C. Give it to
+// Rp2040::new() instead of a real bus and script it from the other side:
+// press buttons, set battery levels, and look at what was sent to the IR
+// and WS2812 outputs.
+//
+// Like the firmware, the first byte of a write selects the register;
+// further bytes written or read go to consecutive registers.
+pub struct Rp2040Sim {
+    regs: [u8; 256],
+    pointer: u8,
+    fw_version: u8,
+    in_bootloader: bool,
+    offline: bool,
+    falling_edges: u32,
+    vbat: f32,
+    vusb: f32,
+    temperature: f32,
+    ir_transmissions: Vec<SimIrTransmission>,
+    ws2812_frames: Vec<Vec<Rgb>>,
+}
+
+impl Default for Rp2040Sim {
+    fn default() -> Self {
+        Self::new(SIM_FW_VERSION)
+    }
+}
+
+impl Rp2040Sim {
+    pub fn new(fw_version: u8) -> Self {
+        let mut sim = Self {
+            regs: [0; 256],
+            pointer: 0,
+            fw_version,
+            in_bootloader: false,
+            offline: false,
+            falling_edges: 0,
+            vbat: 4.0,
+            vusb: 0.0,
+            temperature: 27.0,
+            ir_transmissions: Vec::new(),
+            ws2812_frames: Vec::new(),
+        };
+        sim.regs[Rp2040Reg::Uid0 as usize..][..8].copy_from_slice(&[0x5A; 8]);
+        sim.sample_adc();
+        sim
+    }
+
+    pub fn shared(self) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(self))
+    }
+
+    // While offline, every transfer fails with an address NACK, like a
+    // rebooting RP2040.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn is_in_bootloader(&self) -> bool {
+        self.in_bootloader
+    }
+
+    // Back from the bootloader into the application, e.g. after an update.
+    pub fn leave_bootloader(&mut self, fw_version: u8) {
+        self.fw_version = fw_version;
+        self.in_bootloader = false;
+    }
+
+    pub fn press(&mut self, input: Rp2040Input) {
+        self.set_input(input, true);
+    }
+
+    pub fn release(&mut self, input: Rp2040Input) {
+        self.set_input(input, false);
+    }
+
+    pub fn set_input(&mut self, input: Rp2040Input, active: bool) {
+        self.set_input_bit(input.into(), active);
+    }
+
+    pub fn set_signal(&mut self, signal: Rp2040Signal, active: bool) {
+        self.set_input_bit(signal.into(), active);
+    }
+
+    // Sets the input state and latches the interrupt, if it changed.
+    fn set_input_bit(&mut self, bit: u8, active: bool) {
+        let values = self.reg16(Rp2040Reg::Input1);
+        let new_values = if active { values | (1 << bit) } else { values & !(1 << bit) };
+        if new_values != values {
+            self.set_reg16(Rp2040Reg::Input1, new_values);
+            if !self.interrupt_pending() {
+                self.falling_edges = self.falling_edges.wrapping_add(1);
+            }
+            let interrupts = self.reg16(Rp2040Reg::Interrupt1) | (1 << bit);
+            self.set_reg16(Rp2040Reg::Interrupt1, interrupts);
+        }
+    }
+
+    // The (active low) interrupt line is pulled while any interrupt flag
+    // is set. Reading Interrupt1/Interrupt2 clears them.
+    pub fn interrupt_pending(&self) -> bool {
+        self.reg16(Rp2040Reg::Interrupt1) != 0
+    }
+
+    // The ADC registers only change on the next AdcTrigger, as on the
+    // real thing.
+    pub fn set_battery_voltage(&mut self, volts: f32) {
+        self.vbat = volts;
+    }
+
+    pub fn set_usb_voltage(&mut self, volts: f32) {
+        self.vusb = volts;
+    }
+
+    pub fn set_temperature(&mut self, celsius: f32) {
+        self.temperature = celsius;
+    }
+
+    pub fn set_charging(&mut self, charging: bool) {
+        self.regs[Rp2040Reg::ChargingState as usize] = charging as u8;
+    }
+
+    pub fn set_uid(&mut self, uid: [u8; 8]) {
+        self.regs[Rp2040Reg::Uid0 as usize..][..8].copy_from_slice(&uid);
+    }
+
+    // Level on a GPIO that is configured as input.
+    pub fn set_gpio_input(&mut self, pin: u8, high: bool) {
+        let mask = 1u8 << pin;
+        let reg = &mut self.regs[Rp2040Reg::GpioIn as usize];
+        *reg = if high { *reg | mask } else { *reg & !mask };
+    }
+
+    pub fn gpio_out(&self) -> u8 {
+        self.regs[Rp2040Reg::GpioOut as usize]
+    }
+
+    pub fn gpio_dir(&self) -> u8 {
+        self.regs[Rp2040Reg::GpioDir as usize]
+    }
+
+    pub fn backlight(&self) -> u8 {
+        self.regs[Rp2040Reg::LcdBacklight as usize]
+    }
+
+    pub fn scratch(&self) -> &[u8] {
+        &self.regs[Rp2040Reg::Scratch0 as usize..][..64]
+    }
+
+    pub fn take_ir_transmissions(&mut self) -> Vec<SimIrTransmission> {
+        std::mem::take(&mut self.ir_transmissions)
+    }
+
+    // One entry per Ws2812Trigger, with Ws2812Length colors.
+    pub fn take_ws2812_frames(&mut self) -> Vec<Vec<Rgb>> {
+        std::mem::take(&mut self.ws2812_frames)
+    }
+
+    fn reg16(&self, lo: Rp2040Reg) -> u16 {
+        u16::from_le_bytes([self.regs[lo as usize], self.regs[lo as usize + 1]])
+    }
+
+    fn set_reg16(&mut self, lo: Rp2040Reg, value: u16) {
+        self.regs[lo as usize..][..2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn sample_adc(&mut self) {
+        // 12-bit ADC with 3.3v vref; VBAT and VUSB through a 100k/100k
+        // divider.
+        let to_raw = |volts: f32| ((volts / 3.3 * 4096.0) as i32).clamp(0, 4095) as u16;
+        self.set_reg16(Rp2040Reg::AdcValueVbatLo, to_raw(self.vbat / 2.0));
+        self.set_reg16(Rp2040Reg::AdcValueVusbLo, to_raw(self.vusb / 2.0));
+        // Inverse of the datasheet formula used by read_temperature().
+        let vbe = 0.706 - (self.temperature - 27.0) * 0.001721;
+        self.set_reg16(Rp2040Reg::AdcValueTempLo, to_raw(vbe));
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let reg = self.pointer;
+        self.pointer = self.pointer.wrapping_add(1);
+        if reg == Rp2040Reg::FwVer as u8 {
+            return if self.in_bootloader { BOOTLOADER_FW_VERSION } else { self.fw_version };
+        }
+        let value = self.regs[reg as usize];
+        if reg == Rp2040Reg::Interrupt1 as u8 || reg == Rp2040Reg::Interrupt2 as u8 {
+            self.regs[reg as usize] = 0;
+        }
+        value
+    }
+
+    fn write_byte(&mut self, value: u8) {
+        let reg = self.pointer;
+        self.pointer = self.pointer.wrapping_add(1);
+        let Some(reg) = Rp2040Reg::from_repr(reg) else {
+            return;
+        };
+        match reg {
+            // Read-only.
+            Rp2040Reg::FwVer | Rp2040Reg::GpioIn | Rp2040Reg::Input1 | Rp2040Reg::Input2
+                | Rp2040Reg::Interrupt1 | Rp2040Reg::Interrupt2 | Rp2040Reg::ChargingState
+                | Rp2040Reg::AdcValueVusbLo | Rp2040Reg::AdcValueVusbHi
+                | Rp2040Reg::AdcValueVbatLo | Rp2040Reg::AdcValueVbatHi
+                | Rp2040Reg::AdcValueTempLo | Rp2040Reg::AdcValueTempHi
+                | Rp2040Reg::Uid0 | Rp2040Reg::Uid1 | Rp2040Reg::Uid2 | Rp2040Reg::Uid3
+                | Rp2040Reg::Uid4 | Rp2040Reg::Uid5 | Rp2040Reg::Uid6 | Rp2040Reg::Uid7 => {}
+            Rp2040Reg::AdcTrigger => self.sample_adc(),
+            Rp2040Reg::BlTrigger => {
+                if value == BL_TRIGGER_MAGIC {
+                    self.in_bootloader = true;
+                }
+            }
+            Rp2040Reg::IrTrigger => {
+                self.regs[reg as usize] = value;
+                self.ir_transmissions.push(SimIrTransmission {
+                    address: self.reg16(Rp2040Reg::IrAddressLo),
+                    command: self.regs[Rp2040Reg::IrCommand as usize],
+                    trigger: value,
+                });
+            }
+            Rp2040Reg::Ws2812Trigger => {
+                let length = (self.regs[Rp2040Reg::Ws2812Length as usize] as usize).min(WS2812_MAX_LEDS);
+                let frame = (0..length)
+                    .map(|idx| {
+                        let data = &self.regs[Rp2040Reg::Ws2812Led0Data0 as usize + 4 * idx..][..4];
+                        Rgb::from(u32::from_le_bytes(data.try_into().unwrap()))
+                    })
+                    .collect();
+                self.ws2812_frames.push(frame);
+            }
+            Rp2040Reg::GpioOut | Rp2040Reg::GpioDir => {
+                self.regs[reg as usize] = value;
+                // Outputs read back what we drive.
+                let (dir, out) = (self.gpio_dir(), self.gpio_out());
+                let gpio_in = &mut self.regs[Rp2040Reg::GpioIn as usize];
+                *gpio_in = (*gpio_in & !dir) | (out & dir);
+            }
+            _ => self.regs[reg as usize] = value,
+        }
+    }
+}
+
+impl ErrorType for Rp2040Sim {
+    type Error = ErrorKind;
+}
+
+impl I2c for Rp2040Sim {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> core::result::Result<(), Self::Error> {
+        if address != RP2040_I2C_ADDR || self.offline {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        let mut first_write = true;
+        for op in operations {
+            match op {
+                Operation::Write(data) => {
+                    let mut data = data.iter();
+                    if first_write {
+                        if let Some(&reg) = data.next() {
+                            self.pointer = reg;
+                            first_write = false;
+                        }
+                    }
+                    for &value in data {
+                        self.write_byte(value);
+                    }
+                }
+                Operation::Read(buf) => {
+                    for byte in buf.iter_mut() {
+                        *byte = self.read_byte();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// Async view of a shared Rp2040Sim, for Rp2040Async::new().
+#[cfg(feature = "async")]
+#[derive(Clone)]
+pub struct SimAsyncI2c {
+    sim: Arc<Mutex<Rp2040Sim>>,
+}
+
+#[cfg(feature = "async")]
+impl SimAsyncI2c {
+    pub fn new(sim: Arc<Mutex<Rp2040Sim>>) -> Self {
+        Self { sim }
+    }
+}
+
+#[cfg(feature = "async")]
+impl ErrorType for SimAsyncI2c {
+    type Error = ErrorKind;
+}
+
+#[cfg(feature = "async")]
+impl embedded_hal_async::i2c::I2c for SimAsyncI2c {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> core::result::Result<(), Self::Error> {
+        self.sim.lock().unwrap().transaction(address, operations)
+    }
+}
+
+
+// The simulated interrupt line, for Rp2040::setup_interrupt() or, with
+// the async feature, run_inputs().
+pub struct SimInterruptLine {
+    sim: Arc<Mutex<Rp2040Sim>>,
+}
+
+impl SimInterruptLine {
+    pub fn new(sim: Arc<Mutex<Rp2040Sim>>) -> Self {
+        Self { sim }
+    }
+
+    fn line_low(&self) -> bool {
+        self.sim.lock().unwrap().interrupt_pending()
+    }
+
+    fn falling_edges(&self) -> u32 {
+        self.sim.lock().unwrap().falling_edges
+    }
+}
+
+impl InterruptLine for SimInterruptLine {
+    fn run<F: FnMut(Wakeup) -> Duration>(self, mut on_wake: F) -> Result<Infallible> {
+        let mut wakeup = Wakeup { interrupted: true, line_low: self.line_low() };
+        // Falling edges only, like the ESP32 GPIO interrupt. Counted by
+        // the sim, so an edge while on_wake runs is latched as well.
+        let mut edges = self.falling_edges();
+        loop {
+            let deadline = Instant::now() + on_wake(wakeup);
+            let mut interrupted = false;
+            while Instant::now() < deadline {
+                let now_edges = self.falling_edges();
+                if now_edges != edges {
+                    edges = now_edges;
+                    interrupted = true;
+                    break;
+                }
+                thread::sleep(SIM_INTERRUPT_POLL);
+            }
+            wakeup = Wakeup { interrupted, line_low: self.line_low() };
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl embedded_hal::digital::ErrorType for SimInterruptLine {
+    type Error = Infallible;
+}
+
+// Nothing wakes us when the sim changes, so these keep asking the
+// executor to poll again. Fine for a simulation.
+#[cfg(feature = "async")]
+impl embedded_hal_async::digital::Wait for SimInterruptLine {
+    async fn wait_for_high(&mut self) -> core::result::Result<(), Self::Error> {
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> core::result::Result<(), Self::Error> {
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> core::result::Result<(), Self::Error> {
+        self.wait_for_level(true).await;
+        self.wait_for_level(false).await;
+        Ok(())
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> core::result::Result<(), Self::Error> {
+        self.wait_for_level(false).await;
+        self.wait_for_level(true).await;
+        Ok(())
+    }
+
+    async fn wait_for_any_edge(&mut self) -> core::result::Result<(), Self::Error> {
+        let low = self.line_low();
+        self.wait_for_level(!low).await;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl SimInterruptLine {
+    async fn wait_for_level(&self, low: bool) {
+        std::future::poll_fn(|cx| {
+            if self.line_low() == low {
+                std::task::Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+        }).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mchcoproc::{
+        ChargingState,
+        DecodedInputs,
+        GpioDirection,
+        InputFilter,
+        IrCommand,
+        Rp2040,
+        Rp2040Error,
+        Rp2040InputEvent,
+        Rp2040SignalEvent,
+        RP2040_GPIO_COUNT,
+    };
+
+    type SharedSim = Arc<Mutex<Rp2040Sim>>;
+
+    // Well below the input task's one second watchdog read, so events
+    // have to come from interrupts.
+    const EVENT_TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn sim_rp2040(fw_version: u8) -> (SharedSim, Rp2040<Rp2040Sim>) {
+        let sim = Rp2040Sim::new(fw_version).shared();
+        let mut rp2040 = Rp2040::new(sim.clone());
+        rp2040.get_firmware_version().unwrap();
+        (sim, rp2040)
+    }
+
+    fn sim_read(sim: &SharedSim, reg: Rp2040Reg, buf: &mut [u8]) {
+        sim.lock().unwrap().write_read(RP2040_I2C_ADDR, &[reg.into()], buf).unwrap();
+    }
+
+    #[test]
+    fn scripted_presses_reach_subscribers() {
+        let sim = Rp2040Sim::default().shared();
+        let rp2040 = Rp2040::new(sim.clone());
+        let all = rp2040.subscribe(InputFilter::all());
+        let back = rp2040.subscribe(InputFilter::only([Rp2040Input::ButtonBack]));
+        let _rp2040 = rp2040.setup_interrupt(SimInterruptLine::new(sim.clone())).unwrap();
+
+        for (input, pressed) in [
+            (Rp2040Input::ButtonStart, true),
+            (Rp2040Input::ButtonStart, false),
+            (Rp2040Input::ButtonBack, true),
+        ] {
+            sim.lock().unwrap().set_input(input, pressed);
+            let event = all.receiver.recv_timeout(EVENT_TIMEOUT).unwrap();
+            assert_eq!((event.input, event.is_released), (input, !pressed));
+        }
+        let event = back.receiver.recv_timeout(EVENT_TIMEOUT).unwrap();
+        assert_eq!((event.input, event.is_released), (Rp2040Input::ButtonBack, false));
+        assert!(back.receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn records_ir_and_bootloader() {
+        let sim = Rp2040Sim::default().shared();
+        let mut rp2040 = Rp2040::new(sim.clone());
+        assert_eq!(rp2040.get_firmware_version().unwrap(), SIM_FW_VERSION);
+
+        rp2040.send_ir(&IrCommand::nec(0x04, 0x08)).unwrap();
+        assert_eq!(
+            sim.lock().unwrap().take_ir_transmissions(),
+            [SimIrTransmission { address: 0xFB04, command: 0x08, trigger: 0x01 }]);
+
+        rp2040.enter_bootloader().unwrap();
+        assert!(sim.lock().unwrap().is_in_bootloader());
+        assert_eq!(rp2040.get_firmware_version().unwrap(), BOOTLOADER_FW_VERSION);
+        sim.lock().unwrap().leave_bootloader(0x03);
+        assert_eq!(rp2040.get_firmware_version().unwrap(), 0x03);
+    }
+
+    #[test]
+    fn read_inputs_press_and_release() {
+        let (sim, mut rp2040) = sim_rp2040(0x02);
+        let now = Instant::now();
+
+        sim.lock().unwrap().press(Rp2040Input::ButtonAccept);
+        sim.lock().unwrap().set_signal(Rp2040Signal::FpgaCdone, true);
+        let decoded = rp2040.read_inputs(now).unwrap();
+        assert_eq!(decoded.inputs, [Rp2040InputEvent::new(Rp2040Input::ButtonAccept, false, now)]);
+        assert_eq!(decoded.signals, [Rp2040SignalEvent::new(Rp2040Signal::FpgaCdone, true, now)]);
+        assert!(rp2040.input_state().is_pressed(Rp2040Input::ButtonAccept));
+        assert!(rp2040.read_signal(Rp2040Signal::FpgaCdone).unwrap());
+        assert!(!sim.lock().unwrap().interrupt_pending());
+
+        // Reading cleared the interrupts.
+        assert_eq!(rp2040.read_inputs(now).unwrap(), DecodedInputs::default());
+
+        sim.lock().unwrap().release(Rp2040Input::ButtonAccept);
+        let decoded = rp2040.read_inputs(now).unwrap();
+        assert_eq!(decoded.inputs, [Rp2040InputEvent::new(Rp2040Input::ButtonAccept, true, now)]);
+        assert!(decoded.signals.is_empty());
+        assert_eq!(rp2040.input_state().pressed().count(), 0);
+    }
+
+    #[test]
+    fn power_status() {
+        let (sim, mut rp2040) = sim_rp2040(0x02);
+        sim.lock().unwrap().set_battery_voltage(3.7);
+        sim.lock().unwrap().set_usb_voltage(5.0);
+        sim.lock().unwrap().set_charging(true);
+        let status = rp2040.read_power_status().unwrap();
+        // One ADC step is 1.6 mV.
+        assert!((status.battery_voltage - 3.7).abs() < 0.002, "{}", status.battery_voltage);
+        assert!((status.usb_voltage - 5.0).abs() < 0.002, "{}", status.usb_voltage);
+        assert_eq!(status.charging_state, ChargingState::Charging);
+    }
+
+    #[test]
+    fn gpio_shadow_tracks_writes() {
+        let (sim, mut rp2040) = sim_rp2040(0x02);
+        rp2040.gpio_set_direction(1, GpioDirection::Output).unwrap();
+        rp2040.gpio_set_direction(3, GpioDirection::Output).unwrap();
+        rp2040.gpio_write(1, true).unwrap();
+        rp2040.gpio_write(3, true).unwrap();
+        rp2040.gpio_toggle(1).unwrap();
+        assert_eq!(sim.lock().unwrap().gpio_dir(), 0b1010);
+        assert_eq!(sim.lock().unwrap().gpio_out(), 0b1000);
+        assert_eq!(rp2040.gpio_direction(3).unwrap(), GpioDirection::Output);
+        assert!(!rp2040.gpio_is_set_high(1).unwrap());
+        assert!(rp2040.gpio_read(3).unwrap());
+
+        // Changed behind our back: the shadow only follows after a read.
+        sim.lock().unwrap().write(RP2040_I2C_ADDR, &[Rp2040Reg::GpioOut.into(), 0b0010]).unwrap();
+        assert!(rp2040.gpio_is_set_high(3).unwrap());
+        rp2040.read_gpio_shadow().unwrap();
+        assert!(rp2040.gpio_is_set_high(1).unwrap());
+        assert!(!rp2040.gpio_is_set_high(3).unwrap());
+
+        assert!(matches!(rp2040.gpio_write(RP2040_GPIO_COUNT, true), Err(Rp2040Error::InvalidArgument(_))));
+        assert_eq!(sim.lock().unwrap().gpio_out(), 0b0010);
+    }
+
+    #[test]
+    fn ws2812_register_packing() {
+        let (sim, rp2040) = sim_rp2040(0x02);
+        let colors = [Rgb::RED, Rgb::new(0x11, 0x22, 0x33)];
+        rp2040.set_ws2812_leds(&colors).unwrap();
+
+        // 0x00RRGGBB, little endian.
+        let mut data = [0u8; 8];
+        sim_read(&sim, Rp2040Reg::Ws2812Led0Data0, &mut data);
+        assert_eq!(data, [0x00, 0x00, 0xFF, 0x00, 0x33, 0x22, 0x11, 0x00]);
+        let mut length = [0u8];
+        sim_read(&sim, Rp2040Reg::Ws2812Length, &mut length);
+        assert_eq!(length, [2]);
+
+        rp2040.set_ws2812_led(1, Rgb::BLUE).unwrap();
+        rp2040.trigger_ws2812().unwrap();
+        assert_eq!(sim.lock().unwrap().take_ws2812_frames(), [vec![Rgb::RED, Rgb::BLUE]]);
+
+        let too_many = [Rgb::WHITE; WS2812_MAX_LEDS + 1];
+        assert!(matches!(rp2040.set_ws2812_leds(&too_many), Err(Rp2040Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex, mpsc};
+
+use crate::events::{Rp2040InputEvent, Rp2040SignalEvent};
+
+
+// Events that can be filtered by their Input1/Input2 bit.
+pub trait EventBit: Copy + Send + 'static {
+    fn bit(&self) -> u8;
+}
+
+impl EventBit for Rp2040InputEvent {
+    fn bit(&self) -> u8 { self.input.into() }
+}
+
+impl EventBit for Rp2040SignalEvent {
+    fn bit(&self) -> u8 { self.signal.into() }
+}
+
+
+// Set of Rp2040Input or Rp2040Signal (bits) a subscriber wants.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InputFilter(u16);
+
+impl InputFilter {
+    pub fn all() -> Self {
+        Self(0xffff)
+    }
+
+    pub fn only<T: Into<u8>>(items: impl IntoIterator<Item = T>) -> Self {
+        Self(items.into_iter().fold(0, |mask, item| mask | (1 << item.into())))
+    }
+
+    pub fn matches(self, bit: u8) -> bool {
+        (self.0 >> bit) & 0x1 != 0
+    }
+}
+
+impl Default for InputFilter {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SubscriptionId(u32);
+
+pub struct Subscription<E> {
+    pub id: SubscriptionId,
+    pub receiver: mpsc::Receiver<E>,
+}
+
+
+struct Subscriber<E> {
+    id: SubscriptionId,
+    filter: InputFilter,
+    sender: mpsc::Sender<E>,
+}
+
+struct Registry<E> {
+    next_id: u32,
+    subscribers: Vec<Subscriber<E>>,
+}
+
+
+// Every subscriber gets its own copy of each event that passes its
+// filter. Subscribers whose receiver is gone are dropped on the next
+// publish. Clones share the subscriber list.
+pub struct Subscribers<E> {
+    registry: Arc<Mutex<Registry<E>>>,
+}
+
+impl<E> Clone for Subscribers<E> {
+    fn clone(&self) -> Self {
+        Self { registry: self.registry.clone() }
+    }
+}
+
+impl<E: EventBit> Default for Subscribers<E> {
+    fn default() -> Self {
+        Self { registry: Arc::new(Mutex::new(Registry { next_id: 0, subscribers: Vec::new() })) }
+    }
+}
+
+impl<E: EventBit> Subscribers<E> {
+    pub fn subscribe(&self, filter: InputFilter) -> Subscription<E> {
+        let (sender, receiver) = mpsc::channel();
+        let mut registry = self.registry.lock().unwrap();
+        let id = SubscriptionId(registry.next_id);
+        registry.next_id = registry.next_id.wrapping_add(1);
+        registry.subscribers.push(Subscriber { id, filter, sender });
+        Subscription { id, receiver }
+    }
+
+    // Returns whether it was still subscribed.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut registry = self.registry.lock().unwrap();
+        let before = registry.subscribers.len();
+        registry.subscribers.retain(|sub| sub.id != id);
+        registry.subscribers.len() != before
+    }
+
+    pub fn len(&self) -> usize {
+        self.registry.lock().unwrap().subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn publish(&self, event: E) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.subscribers.retain(|sub| {
+            if !sub.filter.matches(event.bit()) {
+                return true;
+            }
+            let connected = sub.sender.send(event).is_ok();
+            if !connected {
+                log::debug!("Dropping input subscriber {:?}", sub.id);
+            }
+            connected
+        });
+    }
+}
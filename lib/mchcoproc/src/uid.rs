@@ -0,0 +1,27 @@
+use std::fmt;
+
+
+// Unique ID of the RP2040 flash chip, which makes a decent per-badge ID.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BoardUid(pub u64);
+
+impl BoardUid {
+    // Uid0 is the most significant byte, like the pico SDK prints it.
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Self(u64::from_be_bytes(bytes))
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl fmt::Display for BoardUid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016X}", self.0)
+    }
+}
+
+impl From<BoardUid> for u64 {
+    fn from(uid: BoardUid) -> Self { uid.0 }
+}
@@ -0,0 +1,53 @@
+// The RP2040 has room for 10 LEDs in its register map (Ws2812Led0..9).
+pub const WS2812_MAX_LEDS: usize = 10;
+
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+    pub const RED: Rgb = Rgb::new(0xff, 0, 0);
+    pub const GREEN: Rgb = Rgb::new(0, 0xff, 0);
+    pub const BLUE: Rgb = Rgb::new(0, 0, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    // Each LED takes 4 data registers (Data0..Data3). They hold a little
+    // endian 0x00RRGGBB value, so Data0 is blue and Data3 is unused.
+    pub fn to_register_bytes(self) -> [u8; 4] {
+        let value = ((self.r as u32) << 16) | ((self.g as u32) << 8) | (self.b as u32);
+        value.to_le_bytes()
+    }
+}
+
+impl From<(u8, u8, u8)> for Rgb {
+    fn from((r, g, b): (u8, u8, u8)) -> Self { Rgb::new(r, g, b) }
+}
+
+impl From<u32> for Rgb {
+    // From 0xRRGGBB.
+    fn from(value: u32) -> Self {
+        Rgb::new((value >> 16) as u8, (value >> 8) as u8, value as u8)
+    }
+}
+
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Ws2812Mode {
+    #[default]
+    Off = 0,
+    On,
+}
+
+impl From<Ws2812Mode> for u8 {
+    fn from(m: Ws2812Mode) -> Self { m as u8 }
+}
@@ -27,7 +27,7 @@ use embedded_graphics::{
     text::{Baseline, Text},
 };
 
-use ili9341::{DisplaySize240x320, Ili9341, Orientation};
+use ili9341::{DisplaySize240x320, Ili9341, ModeState, Orientation};
 
 #[cfg(feature = "with-framebuffer")]
 use ili9341::DisplayError;
@@ -55,6 +55,7 @@ pub struct Display<'spi> {
     display: MchIli9341<'spi>,
     #[cfg(feature = "with-framebuffer")]
     framebuffer: MchFramebuffer,
+    sleeping: bool,
 }
 
 
@@ -109,6 +110,7 @@ impl<'spi> Display<'spi> {
             // SPI stuff was blazing fast, so if we want back to pure
             // ESP-HAL without ESP-IDF, we could do without.
             framebuffer: MchFramebuffer::new(),
+            sleeping: false,
         }
     }
 
@@ -148,6 +150,32 @@ impl<'spi> Display<'spi> {
             .unwrap();
     }
 
+    // Display off and the ILI9341 in sleep mode. Drawing still works, but
+    // nothing shows until wake().
+    pub fn sleep(&mut self) {
+        if self.sleeping {
+            return;
+        }
+        self.display.display_mode(ModeState::Off).unwrap();
+        self.display.sleep_mode(ModeState::On).unwrap();
+        self.sleeping = true;
+    }
+
+    pub fn wake(&mut self) {
+        if !self.sleeping {
+            return;
+        }
+        self.display.sleep_mode(ModeState::Off).unwrap();
+        // The ILI9341 wants 5ms after sleep out before the next command.
+        Ets::delay_ms(5);
+        self.display.display_mode(ModeState::On).unwrap();
+        self.sleeping = false;
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
     pub fn flush(&mut self) {
         #[cfg(feature = "with-framebuffer")]
         self.framebuffer.flush(&mut self.display).unwrap();
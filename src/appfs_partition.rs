@@ -0,0 +1,68 @@
+use std::io;
+
+use esp_idf_svc::sys::{
+    esp,
+    esp_partition_erase_range,
+    esp_partition_find_first,
+    esp_partition_read,
+    esp_partition_t,
+    esp_partition_write,
+};
+
+use hellomch_appfs::appfs::{Appfs, FlashStorage};
+
+
+// From partitions.csv: appfs, 0x43, 3
+const APPFS_PARTITION_TYPE: u32 = 0x43;
+const APPFS_PARTITION_SUBTYPE: u32 = 3;
+
+
+pub struct AppfsPartition {
+    partition: *const esp_partition_t,
+}
+
+impl AppfsPartition {
+    pub fn find() -> anyhow::Result<Self> {
+        let partition = unsafe {
+            esp_partition_find_first(
+                APPFS_PARTITION_TYPE as _,
+                APPFS_PARTITION_SUBTYPE as _,
+                core::ptr::null(),
+            )
+        };
+        if partition.is_null() {
+            anyhow::bail!("no appfs partition (type {:#x}) found", APPFS_PARTITION_TYPE);
+        }
+        Ok(Self { partition })
+    }
+}
+
+impl FlashStorage for AppfsPartition {
+    fn size(&self) -> usize {
+        // The partition table is static; this pointer stays valid.
+        unsafe { (*self.partition).size as usize }
+    }
+
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        esp!(unsafe {
+            esp_partition_read(self.partition, offset as _, buf.as_mut_ptr() as *mut _, buf.len() as _)
+        }).map_err(io::Error::other)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> io::Result<()> {
+        esp!(unsafe {
+            esp_partition_write(self.partition, offset as _, data.as_ptr() as *const _, data.len() as _)
+        }).map_err(io::Error::other)
+    }
+
+    fn erase(&mut self, offset: usize, len: usize) -> io::Result<()> {
+        esp!(unsafe {
+            esp_partition_erase_range(self.partition, offset as _, len as _)
+        }).map_err(io::Error::other)
+    }
+}
+
+
+pub fn open_appfs() -> anyhow::Result<Appfs<AppfsPartition>> {
+    Ok(Appfs::open(AppfsPartition::find()?)?)
+}
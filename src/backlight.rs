@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use esp_idf_svc::hal::delay::FreeRtos;
+
+use hellomch_mchcoproc::mchcoproc::SharedRp2040;
+
+
+const FADE_STEP_MS: u32 = 20;
+
+pub const BRIGHTNESS_MAX: u8 = 255;
+
+
+// The LCD backlight is driven by the RP2040, not by the ILI9341.
+pub struct Backlight {
+    rp2040: SharedRp2040,
+    brightness: u8,
+}
+
+impl Backlight {
+    pub fn new(rp2040: SharedRp2040) -> anyhow::Result<Self> {
+        let brightness = rp2040.lock().unwrap().get_lcd_backlight()?;
+        Ok(Self { rp2040, brightness })
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) -> anyhow::Result<()> {
+        self.rp2040.lock().unwrap().set_lcd_backlight(brightness)?;
+        self.brightness = brightness;
+        Ok(())
+    }
+
+    // Blocks for (about) duration. Don't hold the rp2040 lock while
+    // calling this.
+    pub fn fade_to(&mut self, brightness: u8, duration: Duration) -> anyhow::Result<()> {
+        let from = self.brightness as i32;
+        let to = brightness as i32;
+        let steps = ((duration.as_millis() as u32) / FADE_STEP_MS).max(1) as i32;
+
+        for step in 1..=steps {
+            let level = from + ((to - from) * step) / steps;
+            self.set_brightness(level as u8)?;
+            if step != steps {
+                FreeRtos::delay_ms(FADE_STEP_MS);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fade_in(&mut self, duration: Duration) -> anyhow::Result<()> {
+        self.fade_to(BRIGHTNESS_MAX, duration)
+    }
+
+    pub fn fade_out(&mut self, duration: Duration) -> anyhow::Result<()> {
+        self.fade_to(0, duration)
+    }
+}
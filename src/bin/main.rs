@@ -6,16 +6,41 @@ use esp_idf_svc::hal::gpio::PinDriver;
 use esp_idf_svc::hal::i2c::{I2cDriver, I2cConfig};
 use esp_idf_svc::hal::prelude::Peripherals;
 use esp_idf_svc::hal::units::Hertz;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 
 use hellomch_mchdisplay::mchdisplay::{Display, Rgb565, RgbColor};
-use hellomch_mchcoproc::mchcoproc::{Rp2040, Rp2040Input, Rp2040InputEvent};
+use hellomch_mchcoproc::mchcoproc::{
+    BatteryGauge,
+    Capabilities,
+    EspInterruptLine,
+    Gesture,
+    GestureRecognizer,
+    InputFilter,
+    IrProfile,
+    PowerPolicy,
+    Rp2040,
+    Rp2040Input,
+    ScreenState,
+    SharedRp2040,
+};
 
+use hellomch::appfs_partition;
+use hellomch::backlight::Backlight;
+use hellomch::boot;
+use hellomch::ir_remote::{self, IrRemotes, PickerAction, ProfilePicker};
+use hellomch::power_policy;
 use hellomch::util;
 
 #[cfg(feature = "with-wifi")]
 use hellomch::wifi;
 
 
+const IDLE_DIM_AFTER: Duration = Duration::from_secs(30);
+const IDLE_OFF_AFTER: Duration = Duration::from_secs(120);
+const IDLE_DIM_BRIGHTNESS: u8 = 32;
+// Redraw and poll the battery this often.
+const LOOP_INTERVAL: Duration = Duration::from_millis(1000);
+
 const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
 #[cfg(feature = "version-from-env")]
 const BUILD_VERSION: &str = env!("BUILD_VERSION");
@@ -81,25 +106,66 @@ fn main() {
         &I2cConfig::new().baudrate(Hertz(400_000)),
     ).unwrap();
     let shared_i2c: SharedI2c = Arc::new(Mutex::new(single_i2c));
-    let (rp2040_event_sender, rp2040_event_receiver) = mpsc::channel::<Rp2040InputEvent>();
-    let rp2040 = Rp2040::new(shared_i2c.clone())
-        .setup_interrupt(peripherals.pins.gpio34, rp2040_event_sender).unwrap();
+    let rp2040 = Rp2040::new(shared_i2c.clone());
+    let rp2040_events = rp2040.subscribe(InputFilter::all());
+    let rp2040 = rp2040
+        .setup_interrupt(EspInterruptLine::new(peripherals.pins.gpio34).unwrap())
+        .unwrap();
 
     let rp2040_fw = rp2040.with_mut(|rp| rp.get_firmware_version().unwrap());
     log::info!("RP2040 firmware version: 0x{:02X}", rp2040_fw);
+    let rp2040_caps = rp2040.with_mut(|rp| rp.capabilities());
+    log::info!("RP2040 capabilities: {:?}", rp2040_caps);
+    match rp2040.with_mut(|rp| rp.read_uid()) {
+        Ok(uid) => log::info!("Board UID: {}", uid),
+        Err(err) => log::warn!("Could not read board UID: {}", err),
+    }
+
+    let mut backlight = Backlight::new(rp2040.clone()).unwrap();
+    backlight.set_brightness(0).unwrap();
 
-    let battery_voltage = rp2040.with_mut(|rp| rp.read_vbat().unwrap());
-    let battery_percent: u8 = (((battery_voltage - 3.6) * 100.0) / (4.1 - 3.6)).clamp(0.0, 100.0) as u8;
+    let mut battery_gauge = BatteryGauge::new();
+    // Older RP2040 firmware has no ADC; leave the battery line out then.
+    let battery_line = match rp2040.with_mut(|rp| rp.read_power_status()) {
+        Ok(status) => {
+            battery_gauge.add_sample(status.battery_voltage, status.charging_state, Instant::now());
+            format!("\nBAT:{}%", battery_gauge.percent().unwrap_or(0))
+        }
+        Err(err) => {
+            log::warn!("Could not read power status: {}", err);
+            String::new()
+        }
+    };
 
-    let s = format!("Hello MCH!\nV:{}\nT:{}\nCO:0x{:02X}\nBAT:{}%", BUILD_VERSION, BUILD_TIMESTAMP, rp2040_fw, battery_percent);
+    let s = format!("Hello MCH!\nV:{}\nT:{}\nCO:0x{:02X}{}", BUILD_VERSION, BUILD_TIMESTAMP, rp2040_fw, battery_line);
     display.clear(Rgb565::WHITE);
     display.println(s.as_str(), 0, 0);
     display.flush();
+    backlight.fade_in(Duration::from_millis(500)).unwrap();
     util::show_memory_status();
 
+    match appfs_partition::open_appfs() {
+        Ok(appfs) => {
+            for app in appfs.list() {
+                log::info!("AppFS: {} ({:?}, v{}, {} bytes)", app.name, app.title, app.version, app.size);
+            }
+            log::info!("AppFS: {} bytes free", appfs.free_space());
+        },
+        Err(err) => log::warn!("Could not open AppFS: {}", err),
+    }
+
+    let nvs_partition = EspDefaultNvsPartition::take().unwrap();
+    if let Err(err) = ir_remote::mount_internal_fat() {
+        log::warn!("Could not mount internal FAT: {}", err);
+    }
+    let mut ir_remotes = IrRemotes::load(nvs_partition.clone());
+    log::info!("IR remote: {}", ir_remotes.active().name);
+    let mut ir_picker: Option<ProfilePicker> = None;
+
     #[cfg(feature = "with-wifi")]
     let maybe_wifi_driver = match wifi::init_wifi_client(
             peripherals.modem,
+            nvs_partition,
             wifi_config::DEFAULT_WIFI_SSID,
             wifi_config::DEFAULT_WIFI_PASSWORD) {
         Ok(wifi_driver) => {
@@ -118,37 +184,48 @@ fn main() {
     let mut n = 0_i32;
     let mut s_display = s.clone();
     let mut s_but = "".to_string();
-    let mut ir_toggle: bool = false;
+    let mut power_policy = PowerPolicy::new(IDLE_DIM_AFTER, IDLE_OFF_AFTER, Instant::now());
+    let mut gestures = GestureRecognizer::default();
 
     loop {
         // Handle all buttons; the timeout here servers as an alternative to FreeRtos::delay_ms(500).
-        match rp2040_event_receiver.recv_timeout(Duration::from_millis(1000)) {
+        // Wake up earlier if a long press or repeat is due.
+        let timeout = gestures.next_deadline().map_or(LOOP_INTERVAL, |deadline| {
+            deadline.saturating_duration_since(Instant::now()).min(LOOP_INTERVAL)
+        });
+        match rp2040_events.receiver.recv_timeout(timeout) {
             Ok(event) => {
-                if !event.is_released {
+                let (deliver, screen_state) = power_policy.on_input(&event, event.timestamp);
+                if let Some(state) = screen_state {
+                    if let Err(err) = power_policy::apply_screen_state(
+                            state, IDLE_DIM_BRIGHTNESS, &mut backlight, &mut display) {
+                        log::error!("Could not switch screen to {:?}: {}", state, err);
+                    }
+                }
+                if deliver && !event.is_released {
                     s_but = format!("BUT: {:?}\n", event.input);
-                    match event.input {
-                        Rp2040Input::ButtonAccept => {
-                            // takes 24ms (in the background)
-                            rp2040.with_mut(|rp| rp.write_ir_trigger_rc5(ir_toggle, 0x10, 13)); // MUTE
-                            ir_toggle = !ir_toggle;
-                        },
-                        Rp2040Input::ButtonBack => {},
-                        Rp2040Input::JoystickDown => {
-                            rp2040.with_mut(|rp| rp.write_ir_trigger_rc5(ir_toggle, 0x10, 17)); // VOL-
-                            ir_toggle = !ir_toggle;
-                        },
-                        Rp2040Input::JoystickUp => {
-                            rp2040.with_mut(|rp| rp.write_ir_trigger_rc5(ir_toggle, 0x10, 16)); // VOL+
-                            ir_toggle = !ir_toggle;
-                        }
-                        Rp2040Input::JoystickLeft => {},
-                        Rp2040Input::JoystickRight => {},
-                        _ => {}, // FIXME
+                }
+                if deliver {
+                    for gesture in gestures.feed(&event) {
+                        dispatch_gesture(gesture, &mut ir_picker, &mut ir_remotes, &rp2040, rp2040_caps, &mut display);
                     }
                 }
                 continue; // NOTE!
             },
             Err(mpsc::RecvTimeoutError::Timeout) => {
+                let fired = gestures.poll(Instant::now());
+                if !fired.is_empty() {
+                    for gesture in fired {
+                        dispatch_gesture(gesture, &mut ir_picker, &mut ir_remotes, &rp2040, rp2040_caps, &mut display);
+                    }
+                    continue; // not time for a redraw
+                }
+                if let Some(state) = power_policy.poll(Instant::now()) {
+                    if let Err(err) = power_policy::apply_screen_state(
+                            state, IDLE_DIM_BRIGHTNESS, &mut backlight, &mut display) {
+                        log::error!("Could not switch screen to {:?}: {}", state, err);
+                    }
+                }
             },
             Err(err) => {
                 log::error!("err? {}", err);
@@ -156,21 +233,40 @@ fn main() {
             },
         }
 
-        let start = Instant::now();
-        if n == 0 {
-            display.clear(Rgb565::BLACK);
-        } else {
-            display.clear(Rgb565::WHITE);
+        // No point in redrawing a screen that is off.
+        if power_policy.state() != ScreenState::Off {
+            let start = Instant::now();
+            if n == 0 {
+                display.clear(Rgb565::BLACK);
+            } else {
+                display.clear(Rgb565::WHITE);
+            }
+            n = (n + 10) % 60;
+            match &ir_picker {
+                Some(picker) => display.println(picker.render(ir_remotes.profiles()).as_str(), 0, 0),
+                None => display.println(
+                    format!("{}\nIR:{}\n{}", s_display, ir_remotes.active().name, s_but).as_str(), n, n),
+            }
+            display.flush();
+            log::info!("Update took {} ms", start.elapsed().as_millis());
+            util::show_memory_status();
         }
-        n = (n + 10) % 60;
-        display.println(format!("{}\n{}", s_display, s_but).as_str(), n, n);
-        display.flush();
-        log::info!("Update took {} ms", start.elapsed().as_millis());
-        util::show_memory_status();
 
         // TEMP: print battery status here
-        let battery_voltage = rp2040.with_mut(|rp| rp.read_vbat().unwrap());
-        println!("Battery voltage: {} V", battery_voltage);
+        match rp2040.with_mut(|rp| rp.read_power_status()) {
+            Ok(status) => {
+                battery_gauge.add_sample(status.battery_voltage, status.charging_state, Instant::now());
+                println!(
+                    "Battery voltage: {} V ({:?}%, {:?} to empty), USB: {} V, {:?}",
+                    status.battery_voltage, battery_gauge.percent(), battery_gauge.time_to_empty(),
+                    status.usb_voltage, status.charging_state);
+            },
+            Err(err) => log::warn!("Could not read power status: {}", err),
+        }
+        match rp2040.with_mut(|rp| rp.read_temperature()) {
+            Ok(temperature) => println!("Board temperature: {:.1} C", temperature),
+            Err(err) => log::warn!("Could not read temperature: {}", err),
+        }
 
         #[cfg(feature = "with-wifi")]
         if let Some(wifi_driver) = maybe_wifi_driver.as_ref() {
@@ -206,3 +302,68 @@ fn main() {
         s_but = "".to_string();
     }
 }
+
+
+// The profile picker gets all gestures while it is open; MENU opens it.
+fn dispatch_gesture(
+    gesture: Gesture,
+    picker: &mut Option<ProfilePicker>,
+    ir_remotes: &mut IrRemotes,
+    rp2040: &SharedRp2040,
+    caps: Capabilities,
+    display: &mut Display,
+) {
+    // Held, so that other Home gestures (and chords) stay free.
+    if gesture == Gesture::LongPress(Rp2040Input::ButtonHome) {
+        boot::return_to_launcher(rp2040);
+    }
+    if let Some(open) = picker.as_mut() {
+        match open.on_gesture(gesture, ir_remotes.profiles().len()) {
+            PickerAction::Nothing => {},
+            PickerAction::Picked(index) => {
+                if let Err(err) = ir_remotes.set_active(index) {
+                    log::error!("Could not save IR remote choice: {}", err);
+                }
+                log::info!("IR remote: {}", ir_remotes.active().name);
+                *picker = None;
+            },
+            PickerAction::Cancelled => *picker = None,
+        }
+    } else if gesture == Gesture::Click(Rp2040Input::ButtonMenu) {
+        *picker = Some(ProfilePicker::new(ir_remotes.active_index()));
+    } else {
+        handle_gesture(gesture, rp2040, ir_remotes.active(), caps);
+        return;
+    }
+
+    // Show the change now instead of at the next redraw.
+    if let Some(open) = picker.as_ref() {
+        display.clear(Rgb565::WHITE);
+        display.println(open.render(ir_remotes.profiles()).as_str(), 0, 0);
+        display.flush();
+    }
+}
+
+fn handle_gesture(gesture: Gesture, rp2040: &SharedRp2040, profile: &IrProfile, caps: Capabilities) {
+    log::info!("Gesture: {:?}", gesture);
+    let input = match gesture {
+        Gesture::Click(input) | Gesture::Repeat(input, _) => input,
+        _ => return,
+    };
+    // Without firmware that can send the protocol, the IR buttons do
+    // nothing.
+    let Some(command) = profile.command_for(input) else {
+        return;
+    };
+    if !command.capability().is_some_and(|cap| caps.contains(cap)) {
+        return;
+    }
+    // takes 24ms (in the background)
+    let sent = match gesture {
+        Gesture::Repeat(..) => rp2040.with_mut(|rp| rp.send_ir_repeat(command)),
+        _ => rp2040.with_mut(|rp| rp.send_ir(command)),
+    };
+    if let Err(err) = sent {
+        log::warn!("Could not send IR: {}", err);
+    }
+}
@@ -0,0 +1,58 @@
+use std::convert::Infallible;
+
+use anyhow::anyhow;
+use esp_idf_svc::hal::reset;
+
+use hellomch_mchcoproc::mchcoproc::SharedRp2040;
+
+use crate::appfs_partition;
+
+
+// The badge bootloader looks at this RTC register (it survives a
+// restart): BOOT_APPFS | handle starts that AppFS app, anything else
+// the launcher. The MCH2022 SDK's exit_to_launcher() writes 0.
+const RTC_CNTL_STORE0_REG: usize = 0x3FF4_804C;
+const BOOT_APPFS: u32 = 0xA500_0000;
+
+
+// Restart into the launcher. The boot parameters in the RP2040 scratch
+// registers are only an extra for launchers that read them; the stock
+// one doesn't, so failing to write them is not fatal.
+pub fn return_to_launcher(rp2040: &SharedRp2040) -> ! {
+    let written = rp2040.lock().unwrap().update_boot_params(|params| {
+        params.boot_target = None;
+        params.return_to_launcher = true;
+    });
+    if let Err(err) = written {
+        log::warn!("Could not leave boot parameters for the launcher: {}", err);
+    }
+    log::info!("Restarting into launcher");
+    restart_into(0);
+}
+
+// Restart into the AppFS app called name. Only returns if there is no
+// such app. The scratch boot parameters are written as an extra here
+// too.
+pub fn boot_app(rp2040: &SharedRp2040, name: &str) -> anyhow::Result<Infallible> {
+    let app = appfs_partition::open_appfs()?
+        .find(name)
+        .ok_or_else(|| anyhow!("no app {:?} in AppFS", name))?;
+    let written = rp2040.lock().unwrap().update_boot_params(|params| {
+        params.boot_target = Some(name.to_string());
+        params.return_to_launcher = false;
+    });
+    if let Err(err) = written {
+        log::warn!("Could not leave boot parameters for {:?}: {}", name, err);
+    }
+    log::info!("Restarting into app {:?} (AppFS handle {})", name, app.handle());
+    restart_into(BOOT_APPFS | app.handle() as u32);
+}
+
+fn restart_into(boot_select: u32) -> ! {
+    // SAFETY: RTC_CNTL_STORE0_REG is a general purpose retention
+    // register, reserved by the badge bootloader for this.
+    unsafe {
+        core::ptr::write_volatile(RTC_CNTL_STORE0_REG as *mut u32, boot_select);
+    }
+    reset::restart();
+}
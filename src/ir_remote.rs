@@ -0,0 +1,174 @@
+use std::fs;
+
+use anyhow::anyhow;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{esp, esp_vfs_fat_mount_config_t, esp_vfs_fat_spiflash_mount_rw_wl, wl_handle_t};
+
+use hellomch_mchcoproc::mchcoproc::{parse_profiles, Gesture, IrProfile, Rp2040Input};
+
+
+// The FAT partition the launcher also uses; see partitions.csv.
+const FAT_BASE_PATH: &std::ffi::CStr = c"/internal";
+const FAT_PARTITION_LABEL: &std::ffi::CStr = c"locfd";
+const FAT_MAX_FILES: i32 = 2;
+const PROFILES_FILE: &str = "/internal/hellomch/ir_remotes.conf";
+
+const NVS_NAMESPACE: &str = "hellomch";
+const NVS_PROFILES_KEY: &str = "ir_remotes"; // used if there is no file
+const NVS_ACTIVE_KEY: &str = "ir_profile";
+const NVS_MAX_PROFILES_LEN: usize = 4000;
+
+// What used to be hardcoded: RC5 TV volume.
+const DEFAULT_PROFILES: &str = "
+[RC5 TV]
+protocol = rc5
+address = 0x10
+button_accept = 13
+joystick_up = 16
+joystick_down = 17
+";
+
+
+// Mount the internal FAT partition at /internal, like the launcher does.
+pub fn mount_internal_fat() -> anyhow::Result<()> {
+    let config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: false,
+        max_files: FAT_MAX_FILES,
+        ..Default::default()
+    };
+    let mut wl_handle: wl_handle_t = 0;
+    esp!(unsafe {
+        esp_vfs_fat_spiflash_mount_rw_wl(
+            FAT_BASE_PATH.as_ptr(), FAT_PARTITION_LABEL.as_ptr(), &config, &mut wl_handle)
+    })?;
+    Ok(())
+}
+
+
+// The loaded profiles and which one the buttons use. Profiles come from
+// the file on the FAT partition, else from NVS, else the built-in one.
+// The choice is kept in NVS.
+pub struct IrRemotes {
+    profiles: Vec<IrProfile>,
+    active: usize,
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl IrRemotes {
+    pub fn load(nvs_partition: EspDefaultNvsPartition) -> Self {
+        let nvs = EspNvs::new(nvs_partition, NVS_NAMESPACE, true)
+            .map_err(|err| log::warn!("Could not open NVS namespace {}: {}", NVS_NAMESPACE, err))
+            .ok();
+
+        let mut profiles = load_profiles(nvs.as_ref());
+        if profiles.is_empty() {
+            profiles = parse_profiles(DEFAULT_PROFILES).unwrap();
+        }
+
+        let mut buf = [0u8; 64];
+        let active_name = match nvs.as_ref() {
+            Some(nvs) => nvs.get_str(NVS_ACTIVE_KEY, &mut buf).ok().flatten(),
+            None => None,
+        };
+        let active = active_name
+            .and_then(|name| profiles.iter().position(|profile| profile.name == name))
+            .unwrap_or(0);
+        Self { profiles, active, nvs }
+    }
+
+    pub fn profiles(&self) -> &[IrProfile] {
+        &self.profiles
+    }
+
+    pub fn active(&self) -> &IrProfile {
+        &self.profiles[self.active]
+    }
+
+    pub fn active_index(&self) -> usize {
+        self.active
+    }
+
+    pub fn set_active(&mut self, index: usize) -> anyhow::Result<()> {
+        let profile = self.profiles.get(index).ok_or_else(|| anyhow!("no IR profile {}", index))?;
+        self.active = index;
+        if let Some(nvs) = self.nvs.as_mut() {
+            nvs.set_str(NVS_ACTIVE_KEY, &profile.name)?;
+        }
+        Ok(())
+    }
+}
+
+fn load_profiles(nvs: Option<&EspNvs<NvsDefault>>) -> Vec<IrProfile> {
+    match fs::read_to_string(PROFILES_FILE) {
+        Ok(text) => match parse_profiles(&text) {
+            Ok(profiles) => return profiles,
+            Err(err) => log::error!("Bad IR profiles in {}: {:#}", PROFILES_FILE, err),
+        },
+        Err(err) => log::info!("No IR profiles in {}: {}", PROFILES_FILE, err),
+    }
+
+    let Some(nvs) = nvs else {
+        return Vec::new();
+    };
+    let mut buf = vec![0u8; NVS_MAX_PROFILES_LEN];
+    match nvs.get_str(NVS_PROFILES_KEY, &mut buf) {
+        Ok(Some(text)) => match parse_profiles(text) {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                log::error!("Bad IR profiles in NVS: {:#}", err);
+                Vec::new()
+            }
+        },
+        Ok(None) => Vec::new(),
+        Err(err) => {
+            log::warn!("Could not read IR profiles from NVS: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+
+pub enum PickerAction {
+    Nothing,
+    Picked(usize),
+    Cancelled,
+}
+
+// The profile picker "app": joystick to move, accept to pick, back to
+// cancel.
+pub struct ProfilePicker {
+    selected: usize,
+}
+
+impl ProfilePicker {
+    pub fn new(selected: usize) -> Self {
+        Self { selected }
+    }
+
+    pub fn on_gesture(&mut self, gesture: Gesture, count: usize) -> PickerAction {
+        match gesture {
+            Gesture::Click(Rp2040Input::JoystickUp) | Gesture::Repeat(Rp2040Input::JoystickUp, _) => {
+                self.selected = self.selected.checked_sub(1).unwrap_or(count - 1);
+            }
+            Gesture::Click(Rp2040Input::JoystickDown) | Gesture::Repeat(Rp2040Input::JoystickDown, _) => {
+                self.selected = (self.selected + 1) % count;
+            }
+            Gesture::Click(Rp2040Input::ButtonAccept) | Gesture::Click(Rp2040Input::JoystickPress) => {
+                return PickerAction::Picked(self.selected);
+            }
+            Gesture::Click(Rp2040Input::ButtonBack) => return PickerAction::Cancelled,
+            _ => {}
+        }
+        PickerAction::Nothing
+    }
+
+    pub fn render(&self, profiles: &[IrProfile]) -> String {
+        let mut text = "IR remote:\n".to_string();
+        for (idx, profile) in profiles.iter().enumerate() {
+            let marker = if idx == self.selected { ">" } else { " " };
+            text += &format!("{} {} ({:?})\n", marker, profile.name, profile.protocol);
+        }
+        text
+    }
+}
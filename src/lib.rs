@@ -1,2 +1,7 @@
+pub mod appfs_partition;
+pub mod backlight;
+pub mod boot;
+pub mod ir_remote;
+pub mod power_policy;
 pub mod util;
 pub mod wifi;
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use hellomch_mchcoproc::mchcoproc::ScreenState;
+use hellomch_mchdisplay::mchdisplay::Display;
+
+use crate::backlight::{Backlight, BRIGHTNESS_MAX};
+
+
+const FADE_DURATION: Duration = Duration::from_millis(300);
+
+
+// Carry out what PowerPolicy decided.
+pub fn apply_screen_state(
+    state: ScreenState,
+    dim_brightness: u8,
+    backlight: &mut Backlight,
+    display: &mut Display,
+) -> anyhow::Result<()> {
+    match state {
+        ScreenState::On => {
+            // Wake is instant; no fading in.
+            display.wake();
+            backlight.set_brightness(BRIGHTNESS_MAX)?;
+        },
+        ScreenState::Dimmed => {
+            backlight.fade_to(dim_brightness, FADE_DURATION)?;
+        },
+        ScreenState::Off => {
+            backlight.fade_out(FADE_DURATION)?;
+            display.sleep();
+        },
+    }
+    Ok(())
+}
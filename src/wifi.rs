@@ -18,11 +18,15 @@ use esp_idf_svc::wifi::{
 use crate::util;
 
 
-pub fn init_wifi_client<'a>(modem: Modem, ssid: &str, password: &str) -> Result<EspWifi<'a>, Error> {
+// The NVS partition can only be taken once, so the caller passes it in.
+pub fn init_wifi_client<'a>(
+    modem: Modem,
+    nvs: EspDefaultNvsPartition,
+    ssid: &str,
+    password: &str,
+) -> Result<EspWifi<'a>, Error> {
     let sys_loop = EspSystemEventLoop::take()?;
     util::show_memory_status();
-    let nvs = EspDefaultNvsPartition::take()?;
-    util::show_memory_status();
 
     let mut wifi_driver = EspWifi::new(
         modem,